@@ -1,16 +1,18 @@
-use std::time::{Duration, Instant};
-use std::{cmp, process};
+use std::time::Instant;
 
-use crate::config::{Config, History};
+use crate::color::Color;
+use crate::command;
+use crate::config::{Config, DescriptionPosition, ErrorDisplay, History};
 use crate::font::Font;
-use crate::selection::{Element, ElementList};
+use crate::keybinds::Mode;
+use crate::launch::{self, LaunchError, Launched, Program};
+use crate::layout::Layout;
+use crate::selection::{Element, ElementList, Source};
+use crate::shapes::{fill_rect, stroke_rect, Rect};
+use crate::truncate::{truncate, Truncation};
 use crate::Args;
 use image::{ImageBuffer, RgbaImage};
-use log::{debug, error};
-use nix::{
-    sys::wait::{waitpid, WaitPidFlag, WaitStatus},
-    unistd::{fork, ForkResult},
-};
+use log::{debug, error, warn};
 use notify_rust::Notification;
 
 pub struct App {
@@ -23,6 +25,30 @@ pub struct App {
     pub history: Option<History>,
     pub last_search_result: Vec<usize>,
     pub args: Args,
+    /// Index of the first visible result in the last drawn frame
+    pub scroll_offset: usize,
+    /// Keep the selection centered, disabled while the selection follows the pointer
+    pub follow_selection: bool,
+    pub mode: Mode,
+    /// Why the last launch failed, cleared when the query changes
+    pub error: Option<String>,
+    /// Why the config files couldn't be reloaded, cleared by the next successful reload
+    pub reload_error: Option<String>,
+    /// Handed to the next launched program, so it may take the focus
+    pub activation_token: Option<String>,
+    /// Custom commands offered for an entry, shown instead of the results
+    pub actions: Option<ActionMenu>,
+    /// Started program whose result is still awaited, with its entry
+    pub launching: Option<(Element, Launched)>,
+}
+
+pub struct ActionMenu {
+    /// Index of the entry in `all_entries`
+    element: usize,
+    /// Selected result, restored when the menu is closed
+    select_index: usize,
+    /// The commands, with their key as description
+    entries: Vec<Element>,
 }
 
 impl App {
@@ -33,7 +59,18 @@ impl App {
         font: Font,
         history: Option<History>,
     ) -> Self {
+        let mode = if config.keybindings.modal {
+            Mode::Normal
+        } else {
+            Mode::Insert
+        };
         let mut app = Self {
+            mode,
+            error: None,
+            reload_error: None,
+            activation_token: None,
+            actions: None,
+            launching: None,
             args,
             config,
             font,
@@ -43,14 +80,44 @@ impl App {
             all_entries,
             query: String::new(),
             last_search_result: Vec::new(),
+            scroll_offset: 0,
+            follow_selection: true,
         };
         app.search();
 
         app
     }
 
+    /// Applies a reloaded config, keeps the query and, where possible, the selection
+    pub fn reload(&mut self, config: Config, font: Font) {
+        if config.keybindings.modal != self.config.keybindings.modal {
+            self.mode = if config.keybindings.modal {
+                Mode::Normal
+            } else {
+                Mode::Insert
+            };
+        }
+        let aliases_changed = config.aliases != self.config.aliases;
+        if aliases_changed {
+            self.all_entries.set_aliases(&config.aliases);
+        }
+        let research = aliases_changed
+            || config.search.match_description != self.config.search.match_description;
+        self.config = config;
+        self.font = font;
+        self.reload_error = None;
+        if research {
+            self.search();
+        }
+    }
+
+    /// Error shown below the results, a failed launch is more recent than a failed reload
+    fn shown_error(&self) -> Option<&String> {
+        self.error.as_ref().or(self.reload_error.as_ref())
+    }
+
     pub fn complete(&mut self) {
-        if !self.select_input {
+        if !self.select_input && self.actions.is_none() {
             let app = (*self
                 .all_entries
                 .as_ref_vec()
@@ -69,22 +136,47 @@ impl App {
         }
     }
 
+    /// Selects the result at `index` without moving the visible part of the list
+    pub fn select(&mut self, index: usize) {
+        if index < self.result_count() {
+            self.select_index = index;
+            self.select_input = false;
+            self.follow_selection = false;
+        }
+    }
+
     pub fn nav_up(&mut self, distance: usize) {
+        self.follow_selection = true;
         if self.select_index > 0 {
             self.select_index = self.select_index.saturating_sub(distance);
-        } else if !self.query.is_empty() {
+        } else if !self.query.is_empty() && self.actions.is_none() {
             self.select_input = true;
         }
     }
 
     pub fn nav_down(&mut self, distance: usize) {
-        if self.select_input && !self.last_search_result.is_empty() {
+        self.follow_selection = true;
+        if self.select_input && self.result_count() > 0 {
             self.select_input = false;
             self.select_index = 0;
-        } else if !self.last_search_result.is_empty()
-            && self.select_index < self.last_search_result.len() - distance
-        {
-            self.select_index += distance;
+        } else if self.result_count() > 0 {
+            self.select_index = (self.select_index + distance).min(self.result_count() - 1);
+        }
+    }
+
+    pub fn nav_first(&mut self) {
+        self.follow_selection = true;
+        if self.result_count() > 0 {
+            self.select_input = false;
+            self.select_index = 0;
+        }
+    }
+
+    pub fn nav_last(&mut self) {
+        self.follow_selection = true;
+        if self.result_count() > 0 {
+            self.select_input = false;
+            self.select_index = self.result_count() - 1;
         }
     }
 
@@ -104,12 +196,69 @@ impl App {
         self.search();
     }
 
-    pub fn execute(&mut self) {
-        let element = if self.select_input {
+    /// Shows the custom commands for the result at `index`, does nothing if there are none
+    pub fn open_actions(&mut self, index: usize) {
+        let custom = &self.config.keybindings.custom;
+        let Some(&element) = self.last_search_result.get(index) else {
+            return;
+        };
+        if custom.is_empty() {
+            return;
+        }
+        let entries = custom
+            .iter()
+            .map(|custom| Element {
+                name: custom.command.clone(),
+                value: custom.command.clone(),
+                description: Some(custom.key.to_string()),
+                keywords: Vec::new(),
+                icon: None,
+                meta: None,
+                base_score: 0,
+                source: Source::Shell,
+            })
+            .collect();
+        self.actions = Some(ActionMenu {
+            element,
+            select_index: index,
+            entries,
+        });
+        self.select_index = 0;
+        self.select_input = false;
+        self.follow_selection = true;
+    }
+
+    /// Returns to the results, returns whether the action menu was open
+    pub fn close_actions(&mut self) -> bool {
+        let Some(menu) = self.actions.take() else {
+            return false;
+        };
+        self.select_index = menu.select_index;
+        self.follow_selection = true;
+        true
+    }
+
+    /// Number of rows in the list, either results or actions
+    fn result_count(&self) -> usize {
+        self.actions
+            .as_ref()
+            .map_or(self.last_search_result.len(), |menu| menu.entries.len())
+    }
+
+    fn selected_element(&self) -> Element {
+        if let Some(menu) = &self.actions {
+            return self.all_entries.as_ref_vec()[menu.element].clone();
+        }
+        if self.select_input {
             Element {
                 name: self.query.to_string(),
                 value: self.query.to_string(),
+                description: None,
+                keywords: Vec::new(),
+                icon: None,
+                meta: None,
                 base_score: 0,
+                source: Source::Shell,
             }
         } else {
             (*self
@@ -118,16 +267,134 @@ impl App {
                 .get(*self.last_search_result.get(self.select_index).unwrap())
                 .unwrap())
             .clone()
-        };
+        }
+    }
+
+    /// Returns whether kickoff is done, it stays open to show launch errors
+    /// and while a launched program is watched, see `finish_launch`
+    pub fn execute(&mut self) -> bool {
+        if let Some(menu) = &self.actions {
+            let command = menu.entries[self.select_index].value.clone();
+            let done = self.execute_custom(&command);
+            self.close_actions();
+            return done;
+        }
+        let element = self.selected_element();
         if self.args.stdout {
             print!("{}", element.value);
             if let Some(mut history) = self.history.take() {
                 history.inc(&element);
                 history.save().unwrap();
             }
+            true
         } else {
-            execute(&element, self.history.take());
+            let program = match &element.source {
+                Source::Path => Program::Executable(&element.value),
+                Source::Shell => Program::Shell(&element.value),
+                Source::Exec(argv) => Program::Exec(argv),
+            };
+            self.launch(&element, program)
+        }
+    }
+
+    /// Runs the command template of a custom keybinding on the selected entry
+    pub fn execute_custom(&mut self, template: &str) -> bool {
+        let element = self.selected_element();
+        let command = command::expand(
+            template,
+            &[
+                ("name", command::quote(&element.name)),
+                ("value", command::quote(&element.value)),
+                ("query", command::quote(&self.query)),
+            ],
+        );
+        debug!("Running custom command: {command}");
+        self.launch(&element, Program::Shell(&command))
+    }
+
+    fn launch(&mut self, element: &Element, program: Program) -> bool {
+        // Empty when the compositor did not answer in time
+        let token = self
+            .activation_token
+            .take()
+            .filter(|token| !token.is_empty());
+        let result = launch::launch(
+            program,
+            &element.name,
+            &self.config.launch,
+            token.as_deref(),
+        );
+        match result {
+            Ok(launched) => {
+                self.launching = Some((element.clone(), launched));
+                false
+            }
+            Err(err) => self.launched(element, Err(err)),
+        }
+    }
+
+    /// Checks on the program started last, once its result is known
+    /// returns whether kickoff is done like `execute`
+    pub fn finish_launch(&mut self) -> Option<bool> {
+        let result = self.launching.as_mut()?.1.poll()?;
+        let (element, _) = self.launching.take()?;
+        Some(self.launched(&element, result))
+    }
+
+    /// Blocks until the result of the program started last is known
+    pub fn wait_launch(&mut self) -> bool {
+        match self.launching.take() {
+            Some((element, launched)) => self.launched(&element, launched.wait()),
+            None => false,
+        }
+    }
+
+    /// Counts a successful launch in the history or shows why it failed
+    fn launched(&mut self, element: &Element, result: Result<(), LaunchError>) -> bool {
+        let err = match result {
+            Ok(()) => {
+                if let Some(history) = &mut self.history {
+                    history.inc(element);
+                    if let Err(e) = history.save() {
+                        error!("{e}");
+                    }
+                }
+                return true;
+            }
+            Err(err) => err,
+        };
+
+        let message = format!("Failed to launch {}: {err}", element.name);
+        error!("{message}");
+        if self.config.launch.errors == ErrorDisplay::Notification {
+            let notification = Notification::new()
+                .summary("Kickoff")
+                .body(&message)
+                .timeout(5000)
+                .show();
+            match notification {
+                Ok(_) => return true,
+                Err(e) => warn!("Unable to show notification: {e}"),
+            }
+        }
+        self.error = Some(message);
+        false
+    }
+
+    /// Forgets the selected entry in the history, it keeps its place in the results
+    /// until the next search
+    pub fn remove_history(&mut self) {
+        if self.select_input {
+            return;
+        }
+        let element = self.selected_element();
+        if let Some(history) = &mut self.history {
+            history.remove(&element);
+            if let Err(e) = history.save() {
+                error!("{e}");
+            }
         }
+        self.all_entries.reset_score(&element.name);
     }
 
     pub fn insert(&mut self, input: &str) {
@@ -136,11 +403,16 @@ impl App {
     }
 
     pub fn search(&mut self) {
+        self.error = None;
+        self.actions = None;
         self.last_search_result = Vec::new();
-        let search_results = self.all_entries.search(&self.query);
+        let search_results = self
+            .all_entries
+            .search(&self.query, self.config.search.match_description);
 
         self.select_input = false;
         self.select_index = 0;
+        self.follow_selection = true;
         if search_results.is_empty() {
             self.select_input = true;
         }
@@ -157,41 +429,87 @@ impl App {
 
     pub fn draw(&mut self, width: u32, height: u32, scale: i32) -> RgbaImage {
         let frame_draw_start = Instant::now();
-        let search_results: Vec<&Element> = self
-            .last_search_result
-            .iter()
-            .map(|index| *self.all_entries.as_ref_vec().get(*index).unwrap())
-            .collect();
+        let layout = self.layout(width, height, scale);
+        self.scroll_offset = layout.offset;
+        let search_results: Vec<&Element> = match &self.actions {
+            Some(menu) => menu.entries.iter().collect(),
+            None => self
+                .last_search_result
+                .iter()
+                .map(|index| *self.all_entries.as_ref_vec().get(*index).unwrap())
+                .collect(),
+        };
 
-        self.font.set_scale(scale);
-        let padding = self.config.padding * scale as u32;
-        let font_size = self.config.font_size * scale as f32;
+        let padding = layout.padding;
+        let font_size = layout.font_size;
+        let colors = &self.config.colors;
+        let style = &self.config.style;
+        let highlight_radius = (style.highlight_corner_radius * scale as u32) as f32;
+
+        let window_rect = Rect::new(0., 0., width as f32, height as f32);
+        let window_radius = (style.corner_radius * scale as u32) as f32;
+        let mut img = if window_radius > 0. {
+            let mut img = RgbaImage::new(width, height);
+            fill_rect(&mut img, window_rect, window_radius, &colors.background);
+            img
+        } else {
+            ImageBuffer::from_pixel(width, height, colors.background.to_rgba())
+        };
+        stroke_rect(
+            &mut img,
+            window_rect,
+            window_radius,
+            (style.border_width * scale as u32) as f32,
+            &colors.border,
+        );
+
+        let query_rect = layout.query_rect();
+        fill_rect(
+            &mut img,
+            query_rect,
+            highlight_radius,
+            &colors.query_background,
+        );
+        stroke_rect(
+            &mut img,
+            query_rect,
+            highlight_radius,
+            (style.query_border_width * scale as u32) as f32,
+            &colors.query_border,
+        );
+        if self.select_input {
+            fill_rect(
+                &mut img,
+                query_rect,
+                highlight_radius,
+                &colors.selection_background,
+            );
+        }
 
-        let mut img =
-            ImageBuffer::from_pixel(width, height, self.config.colors.background.to_rgba());
         let prompt = match &self.args.prompt {
             Some(prompt) => prompt,
             None => &self.config.prompt,
         };
+        let keybindings = &self.config.keybindings;
+        let prompt = match self.mode {
+            _ if !keybindings.modal => prompt.to_string(),
+            Mode::Normal => format!("{}{prompt}", keybindings.normal_prompt),
+            Mode::Insert => format!("{}{prompt}", keybindings.insert_prompt),
+        };
         let prompt_width = if prompt.is_empty() {
             0
         } else {
-            let (width, _) = self.font.render(
-                prompt,
-                &self.config.colors.prompt,
-                &mut img,
-                padding,
-                padding,
-                None,
-            );
+            let (width, _) =
+                self.font
+                    .render(&prompt, &colors.prompt, &mut img, padding, padding, None);
             width + (font_size * 0.2) as u32
         };
 
         if !self.query.is_empty() {
             let color = if self.select_input {
-                &self.config.colors.text_selected
+                &colors.text_selected
             } else {
-                &self.config.colors.text_query
+                &colors.text_query
             };
             self.font.render(
                 &self.query,
@@ -203,85 +521,167 @@ impl App {
             );
         }
 
-        let spacer = (1.5 * font_size) as u32;
-        let max_entries = ((height.saturating_sub(2 * padding).saturating_sub(spacer)) as f32
-            / (font_size * 1.2)) as usize;
-        let offset = if self.select_index > (max_entries / 2) {
-            self.select_index - max_entries / 2
-        } else {
-            0
-        };
-
-        for (i, matched) in search_results
-            .iter()
-            .enumerate()
-            .take(cmp::min(max_entries + offset, search_results.len()))
-            .skip(offset)
-        {
+        for i in layout.visible_entries() {
+            let matched = search_results[i];
+            let y = layout.entry_y(i);
             let color = if i == self.select_index && !self.select_input {
-                &self.config.colors.text_selected
+                fill_rect(
+                    &mut img,
+                    layout.entry_rect(i),
+                    highlight_radius,
+                    &colors.selection_background,
+                );
+                &colors.text_selected
             } else {
-                &self.config.colors.text
+                &colors.text
             };
-            self.font.render(
-                &matched.name,
-                color,
+            self.draw_entry(
                 &mut img,
-                padding,
-                padding + spacer + (i - offset) as u32 * (font_size * 1.2) as u32,
-                Some((width - (padding * 2)) as usize),
+                matched,
+                color,
+                (padding, y),
+                width.saturating_sub(2 * padding),
+                layout.row_height as u32,
             );
         }
 
+        if let Some(error) = self.shown_error() {
+            let max_width = width.saturating_sub(2 * padding) as f32;
+            let error = truncate(error, max_width, Truncation::End, &[], |text| {
+                self.font.measure(text)
+            });
+            let y = layout.entry_y(layout.offset + layout.max_entries);
+            self.font
+                .render(&error, &colors.text_error, &mut img, padding, y, None);
+        }
+
         let elapsed = frame_draw_start.elapsed();
         debug!("frame time: {:.2?}", elapsed);
 
         img
     }
-}
 
-fn execute(elem: &Element, history: Option<History>) {
-    match unsafe { fork() } {
-        Ok(ForkResult::Parent { child }) => {
-            // We can't make that to long, since for some reason, even if this would be after a fork and the main programm exits,
-            // wayland keeps the window alive
-            std::thread::sleep(Duration::new(0, 100_000_000));
-            match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
-                Ok(WaitStatus::StillAlive | WaitStatus::Exited(_, 0)) => {
-                    if let Some(mut history) = history {
-                        history.inc(elem);
-                        match history.save() {
-                            Ok(()) => {}
-                            Err(e) => {
-                                error!("{e}");
-                            }
-                        };
-                    }
-                }
-                Ok(_) => {
-                    /* Every non 0 statuscode holds no information since it's
-                    origin can be the started application or a file not found error.
-                    In either case the error has already been logged and does not
-                    need to be handled here. */
-                }
-                Err(err) => error!("{err}"),
-            }
+    /// Computes the positions of query and results for a buffer of the given size
+    pub fn layout(&mut self, width: u32, height: u32, scale: i32) -> Layout {
+        self.font.set_scale(scale);
+        let style = &self.config.style;
+        let padding = self.config.padding * scale as u32;
+        let font_size = self.config.font_size * scale as f32;
+        let row_height = font_size * style.line_spacing;
+        let spacer = (style.query_spacing * font_size) as u32;
+        let entry_lines = match style.description_position {
+            DescriptionPosition::Right => 1.,
+            DescriptionPosition::Below => 2.,
+        };
+        // The error takes the place of the last row
+        let error_height = if self.shown_error().is_some() {
+            row_height
+        } else {
+            0.
+        };
+        let available = height.saturating_sub(2 * padding).saturating_sub(spacer) as f32;
+        let max_entries =
+            ((available - error_height).max(0.) / (row_height * entry_lines)) as usize;
+
+        let offset = if self.follow_selection {
+            self.select_index.saturating_sub(max_entries / 2)
+        } else if self.select_index < self.scroll_offset {
+            self.select_index
+        } else if self.select_index >= self.scroll_offset + max_entries {
+            (self.select_index + 1).saturating_sub(max_entries)
+        } else {
+            self.scroll_offset
+        };
+
+        Layout {
+            width,
+            padding,
+            font_size,
+            text_height: self.font.line_height(),
+            row_height,
+            entry_lines,
+            spacer,
+            max_entries,
+            offset,
+            result_count: self.result_count(),
         }
+    }
 
-        Ok(ForkResult::Child) => {
-            let err = exec::Command::new("sh").args(&["-c", &elem.value]).exec();
+    /// Draws the name and description of an element, starting at the top left `position`
+    fn draw_entry(
+        &self,
+        img: &mut RgbaImage,
+        element: &Element,
+        color: &Color,
+        position: (u32, u32),
+        max_width: u32,
+        row_height: u32,
+    ) {
+        let (x, y) = position;
+        let style = &self.config.style;
+        let gap = self.config.font_size * self.font.scale() as f32;
+        let description = element.description.as_deref().unwrap_or_default();
 
-            // Won't be executed when exec was successful
-            error!("{err}");
+        // An inline description takes at most half of the row
+        let name_width = match style.description_position {
+            DescriptionPosition::Right if !description.is_empty() => {
+                max_width as f32 - (self.font.measure(description) + gap).min(max_width as f32 / 2.)
+            }
+            _ => max_width as f32,
+        };
+        let name = truncate(
+            &element.name,
+            name_width,
+            style.truncation,
+            &ElementList::match_indices(&element.name, &self.query),
+            |text| self.font.measure(text),
+        );
+        let rendered_name_width = self.font.measure(&name);
+        let right_aligned = style.right_align_rtl && Font::is_rtl(&name);
+        let name_x = if right_aligned {
+            x + max_width.saturating_sub(rendered_name_width as u32)
+        } else {
+            x
+        };
+        self.font.render(
+            &name,
+            color,
+            img,
+            name_x,
+            y,
+            Some((max_width - (name_x - x)) as usize),
+        );
 
-            Notification::new()
-                .summary("Kickoff")
-                .body(&format!("{err}"))
-                .timeout(5000)
-                .show()
-                .unwrap();
-            process::exit(2);
+        if description.is_empty() {
+            return;
         }
-        Err(e) => error!("{e}"),
+        let (description_y, description_width) = match style.description_position {
+            DescriptionPosition::Right => (y, max_width as f32 - rendered_name_width - gap),
+            DescriptionPosition::Below => (y + row_height, max_width as f32),
+        };
+        if description_width < gap {
+            return;
+        }
+        let description = truncate(
+            description,
+            description_width,
+            Truncation::End,
+            &[],
+            |text| self.font.measure(text),
+        );
+        let description_x =
+            if right_aligned == (style.description_position == DescriptionPosition::Right) {
+                x
+            } else {
+                x + max_width.saturating_sub(self.font.measure(&description) as u32)
+            };
+        self.font.render(
+            &description,
+            &self.config.colors.text_description,
+            img,
+            description_x,
+            description_y,
+            Some(description_width as usize),
+        );
     }
 }
@@ -1,10 +1,10 @@
 use image::Rgba;
 use serde::de::{self, Visitor};
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 use std::str::FromStr;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Color(pub u8, pub u8, pub u8, pub u8);
 
 impl From<css_color::Rgba> for Color {
@@ -24,6 +24,25 @@ impl Color {
     }
 }
 
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Undo the premultiplied alpha
+        let channel = |value: u8| match self.3 {
+            0 => 0,
+            alpha => (f32::from(value) * 255. / f32::from(alpha))
+                .round()
+                .min(255.) as u8,
+        };
+        serializer.serialize_str(&format!(
+            "#{:02x}{:02x}{:02x}{:02x}",
+            channel(self.0),
+            channel(self.1),
+            channel(self.2),
+            self.3
+        ))
+    }
+}
+
 impl<'de> Deserialize<'de> for Color {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -46,6 +65,9 @@ impl Visitor<'_> for ColorVisitor {
         E: de::Error,
     {
         let c = css_color::Rgba::from_str(value);
-        c.map_or_else(|_| Err(de::Error::custom("")), |c| Ok(Color::from(c)))
+        c.map_or_else(
+            |_| Err(de::Error::invalid_value(de::Unexpected::Str(value), &self)),
+            |c| Ok(Color::from(c)),
+        )
     }
 }
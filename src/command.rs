@@ -0,0 +1,121 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Quotes `text` so that `sh` reads it as a single word
+pub fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+/// Quotes and joins `argv` into a command that `sh` runs as is
+pub fn join(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Replaces the `{placeholder}`s in `template` by their value, values have to be quoted by the caller.
+/// Unknown placeholders are kept as they are, substituted values are not expanded again.
+pub fn expand(template: &str, values: &[(&str, String)]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                result.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Puts the program into the launch `wrapper`.
+/// `{cmd}` is replaced by `command`, which has to be quoted already, `{name}` by the name
+/// of the entry reduced to characters allowed in systemd unit names and `{random}` by `random`.
+pub fn wrap(wrapper: &str, command: &str, name: &str, random: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    expand(
+        wrapper,
+        &[
+            ("cmd", command.to_string()),
+            ("name", quote(&name)),
+            ("random", quote(random)),
+        ],
+    )
+}
+
+/// Short random hex string to tell launches of the same entry apart
+pub fn random_id() -> String {
+    format!("{:08x}", RandomState::new().build_hasher().finish() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_test() {
+        assert_eq!(quote("foo bar"), "'foo bar'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote(""), "''");
+        assert_eq!(join(&["ls".into(), "a b".into()]), "'ls' 'a b'");
+    }
+
+    #[test]
+    fn expand_test() {
+        let values = [
+            ("name", quote("Fire fox")),
+            ("value", quote("{name}; rm -rf ~")),
+        ];
+        assert_eq!(
+            expand("xdg-open {value}", &values),
+            "xdg-open '{name}; rm -rf ~'"
+        );
+        assert_eq!(expand("wl-copy {name}", &values), "wl-copy 'Fire fox'");
+        assert_eq!(
+            expand("awk '{print}' {unknown} {", &values),
+            "awk '{print}' {unknown} {"
+        );
+    }
+
+    #[test]
+    fn wrap_test() {
+        assert_eq!(
+            wrap(
+                "systemd-run --user --scope --unit=app-kickoff-{name}-{random} -- {cmd}",
+                r"sh -c 'firefox --new-window '\''about:blank'\'''",
+                "Firefox (Wayland)",
+                "1a2b3c4d"
+            ),
+            r"systemd-run --user --scope --unit=app-kickoff-'Firefox__Wayland_'-'1a2b3c4d' -- sh -c 'firefox --new-window '\''about:blank'\'''"
+        );
+        assert_eq!(
+            wrap("uwsm app -- {cmd}", "'foot'", "Foot", "0"),
+            "uwsm app -- 'foot'"
+        );
+        assert_eq!(random_id().len(), 8);
+    }
+}
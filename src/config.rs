@@ -1,29 +1,49 @@
 use crate::color::Color;
-use crate::keybinds::{KeyCombo, Modifiers};
+use crate::keybinds::{KeyCombo, KeySequence, Modifiers};
 use crate::selection::Element;
-use log::info;
+use crate::truncate::Truncation;
+use log::{info, warn};
 use smithay_client_toolkit::seat::keyboard::{Keysym, Modifiers as ModifiersState};
-use std::fmt::Debug;
-use std::fs::{read_to_string, write};
-use std::path::PathBuf;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{self, Debug};
+use std::fs::{read_dir, read_to_string, write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
+use toml_edit::{ImDocument, Key, TableLike, Value};
 use xdg::BaseDirectories;
 
-use serde::{Deserialize, Serialize};
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use serde::{forward_to_deserialize_any, Deserialize, Serialize, Serializer};
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(default)]
 pub struct ColorConfig {
     pub background: Color,
     pub text: Color,
     pub text_query: Color,
     pub text_selected: Color,
+    pub text_description: Color,
+    pub text_error: Color,
     pub prompt: Color,
+    pub border: Color,
+    pub selection_background: Color,
+    pub query_background: Color,
+    pub query_border: Color,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(default)]
 pub struct KeybindingsConfig {
+    /// Start in normal mode, `exit` switches from insert to normal mode
+    pub modal: bool,
+    /// Milliseconds to wait for the next key of a sequence
+    pub sequence_timeout: u64,
+    /// Shown in front of the prompt in modal mode
+    pub normal_prompt: String,
+    pub insert_prompt: String,
     pub delete: Vec<KeyCombo>,
     pub delete_word: Vec<KeyCombo>,
     pub execute: Vec<KeyCombo>,
@@ -31,38 +51,143 @@ pub struct KeybindingsConfig {
     pub complete: Vec<KeyCombo>,
     pub nav_up: Vec<KeyCombo>,
     pub nav_down: Vec<KeyCombo>,
+    pub page_up: Vec<KeyCombo>,
+    pub page_down: Vec<KeyCombo>,
+    pub first: Vec<KeyCombo>,
+    pub last: Vec<KeyCombo>,
     pub exit: Vec<KeyCombo>,
+    pub custom: Vec<CustomKeybinding>,
+    pub normal: NormalKeybindingsConfig,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+/// Key sequences used in normal mode
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct NormalKeybindingsConfig {
+    pub execute: Vec<KeySequence>,
+    pub paste: Vec<KeySequence>,
+    pub complete: Vec<KeySequence>,
+    pub nav_up: Vec<KeySequence>,
+    pub nav_down: Vec<KeySequence>,
+    pub page_up: Vec<KeySequence>,
+    pub page_down: Vec<KeySequence>,
+    pub first: Vec<KeySequence>,
+    pub last: Vec<KeySequence>,
+    pub insert: Vec<KeySequence>,
+    pub remove_history: Vec<KeySequence>,
+    pub exit: Vec<KeySequence>,
+}
+
+/// Runs `command` on the selected entry, `{name}`, `{value}` and `{query}` are replaced
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CustomKeybinding {
+    pub key: KeyCombo,
+    pub command: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(default)]
 pub struct HistoryConfig {
     pub decrease_interval: u64,
 }
 
-#[derive(Deserialize, Clone, Debug, Default)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 #[serde(default)]
 pub struct SearchConfig {
     pub show_hidden_files: bool,
+    pub match_description: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct WindowConfig {
+    pub output: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct LaunchConfig {
+    /// Runs typed input, lines of files and stdin, custom commands and the wrapper
+    pub shell: String,
+    /// Command launched programs are wrapped in, see `command::wrap`
+    pub wrapper: Option<String>,
+    /// Environment variables set for launched programs
+    pub env: HashMap<String, String>,
+    /// Defaults to `$HOME`
+    pub working_directory: Option<PathBuf>,
+    /// Milliseconds a launched program is watched for an early unsuccessful exit, 0 disables it
+    pub early_exit_timeout: u64,
+    pub errors: ErrorDisplay,
+}
+
+/// Where launch failures are shown
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorDisplay {
+    /// In a line at the bottom of the window, which stays open
+    #[default]
+    Inline,
+    /// As desktop notification, falls back to inline if no notification daemon is running
+    Notification,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct StyleConfig {
+    #[serde(serialize_with = "serialize_f32")]
+    pub line_spacing: f32,
+    #[serde(serialize_with = "serialize_f32")]
+    pub query_spacing: f32,
+    pub border_width: u32,
+    pub corner_radius: u32,
+    pub query_border_width: u32,
+    pub highlight_corner_radius: u32,
+    pub right_align_rtl: bool,
+    pub truncation: Truncation,
+    pub description_position: DescriptionPosition,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DescriptionPosition {
+    /// Right aligned in the same row as the name
+    #[default]
+    Right,
+    /// In a second line below the name
+    Below,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(default)]
 pub struct Config {
+    /// Files merged before this one, relative to its directory. Only used while loading.
+    #[serde(skip_serializing)]
+    pub include: Vec<PathBuf>,
+    /// Name of the theme in `themes/<name>.toml`, see `THEME_KEYS`
+    pub theme: Option<String>,
     pub prompt: String,
     pub padding: u32,
     pub font: Option<String>,
     pub fonts: Vec<String>,
+    #[serde(serialize_with = "serialize_f32")]
     pub font_size: f32,
     pub colors: ColorConfig,
+    pub style: StyleConfig,
     pub history: HistoryConfig,
     pub keybindings: KeybindingsConfig,
     pub search: SearchConfig,
+    pub window: WindowConfig,
+    pub launch: LaunchConfig,
+    pub aliases: HashMap<String, String>,
 }
 
 impl Default for KeybindingsConfig {
     fn default() -> Self {
         Self {
+            modal: false,
+            sequence_timeout: 1000,
+            normal_prompt: "[N] ".to_string(),
+            insert_prompt: "[I] ".to_string(),
             delete: vec![
                 KeyCombo::new(Modifiers::default(), Keysym::BackSpace),
                 KeyCombo::new(Modifiers::default(), Keysym::Delete),
@@ -115,7 +240,68 @@ impl Default for KeybindingsConfig {
                 KeyCombo::new(Modifiers::default(), Keysym::Down),
                 KeyCombo::new(Modifiers::default(), Keysym::KP_Down),
             ],
+            page_up: vec![
+                KeyCombo::new(Modifiers::default(), Keysym::Page_Up),
+                KeyCombo::new(Modifiers::default(), Keysym::KP_Page_Up),
+            ],
+            page_down: vec![
+                KeyCombo::new(Modifiers::default(), Keysym::Page_Down),
+                KeyCombo::new(Modifiers::default(), Keysym::KP_Page_Down),
+            ],
+            first: vec![
+                KeyCombo::new(Modifiers::default(), Keysym::Home),
+                KeyCombo::new(Modifiers::default(), Keysym::KP_Home),
+            ],
+            last: vec![
+                KeyCombo::new(Modifiers::default(), Keysym::End),
+                KeyCombo::new(Modifiers::default(), Keysym::KP_End),
+            ],
             exit: vec![KeyCombo::new(Modifiers::default(), Keysym::Escape)],
+            custom: Vec::new(),
+            normal: NormalKeybindingsConfig::default(),
+        }
+    }
+}
+
+impl Default for NormalKeybindingsConfig {
+    fn default() -> Self {
+        let key = |key| KeyCombo::new(Modifiers::default(), key);
+        let ctrl = |key| {
+            KeyCombo::new(
+                ModifiersState {
+                    ctrl: true,
+                    ..ModifiersState::default()
+                }
+                .into(),
+                key,
+            )
+        };
+        let shift = |key| {
+            KeyCombo::new(
+                ModifiersState {
+                    shift: true,
+                    ..ModifiersState::default()
+                }
+                .into(),
+                key,
+            )
+        };
+        Self {
+            execute: vec![key(Keysym::Return).into(), key(Keysym::KP_Enter).into()],
+            paste: vec![key(Keysym::p).into()],
+            complete: vec![key(Keysym::Tab).into()],
+            nav_up: vec![key(Keysym::k).into(), key(Keysym::Up).into()],
+            nav_down: vec![key(Keysym::j).into(), key(Keysym::Down).into()],
+            page_up: vec![ctrl(Keysym::b).into(), key(Keysym::Page_Up).into()],
+            page_down: vec![ctrl(Keysym::f).into(), key(Keysym::Page_Down).into()],
+            first: vec![
+                vec![key(Keysym::g), key(Keysym::g)].into(),
+                key(Keysym::Home).into(),
+            ],
+            last: vec![shift(Keysym::G).into(), key(Keysym::End).into()],
+            insert: vec![key(Keysym::slash).into(), key(Keysym::i).into()],
+            remove_history: vec![vec![key(Keysym::d), key(Keysym::d)].into()],
+            exit: vec![key(Keysym::Escape).into(), key(Keysym::q).into()],
         }
     }
 }
@@ -127,24 +313,64 @@ impl Default for ColorConfig {
             text: Color(255, 255, 255, 255),
             text_query: Color(229, 192, 123, 255),
             text_selected: Color(97, 175, 239, 255),
+            text_description: Color(130, 137, 151, 255),
+            text_error: Color(224, 108, 117, 255),
+            border: Color::default(),
+            selection_background: Color::default(),
+            query_background: Color::default(),
+            query_border: Color::default(),
         }
     }
 }
 impl Default for Config {
     fn default() -> Self {
         Self {
+            include: Vec::new(),
+            theme: None,
             prompt: String::new(),
             padding: 100,
             font: None,
             fonts: vec![],
             font_size: 32.,
             colors: ColorConfig::default(),
+            style: StyleConfig::default(),
             history: HistoryConfig::default(),
             keybindings: KeybindingsConfig::default(),
             search: SearchConfig::default(),
+            window: WindowConfig::default(),
+            launch: LaunchConfig::default(),
+            aliases: HashMap::new(),
         }
     }
 }
+impl Default for StyleConfig {
+    fn default() -> Self {
+        Self {
+            line_spacing: 1.2,
+            query_spacing: 1.5,
+            border_width: 0,
+            corner_radius: 0,
+            query_border_width: 0,
+            highlight_corner_radius: 0,
+            right_align_rtl: false,
+            truncation: Truncation::default(),
+            description_position: DescriptionPosition::default(),
+        }
+    }
+}
+impl Default for LaunchConfig {
+    fn default() -> Self {
+        Self {
+            shell: "sh".to_string(),
+            wrapper: None,
+            env: HashMap::new(),
+            working_directory: None,
+            early_exit_timeout: 300,
+            errors: ErrorDisplay::default(),
+        }
+    }
+}
+
 impl Default for HistoryConfig {
     fn default() -> Self {
         Self {
@@ -154,23 +380,581 @@ impl Default for HistoryConfig {
 }
 
 impl Config {
-    pub fn load(config_path: Option<PathBuf>) -> Result<Self, Box<dyn std::error::Error>> {
-        let xdg_dirs = BaseDirectories::with_prefix("kickoff");
+    /// Fonts in order of preference, `font` is kept for older configs
+    pub fn font_names(&self) -> Vec<String> {
+        self.font.iter().chain(&self.fonts).cloned().collect()
+    }
+
+    /// Loads the config given on the command line or the layered config from the config
+    /// directories, writes the default config if there is none.
+    pub fn load(
+        config_path: Option<PathBuf>,
+        overrides: &Overrides,
+    ) -> Result<LoadedConfig, Diagnostic> {
+        let mut files = Self::files(config_path);
+        if files.is_empty() {
+            let config_file: PathBuf = BaseDirectories::with_prefix("kickoff")
+                .place_config_file("config.toml")
+                .map_err(|e| Diagnostic::error(None, e.to_string()))?;
+            let default = include_bytes!("../assets/default_config.toml");
+            write(&config_file, default)
+                .map_err(|e| Diagnostic::error(Some(&config_file), e.to_string()))?;
+            files.push(config_file);
+        }
+
+        Self::load_files(&files, overrides)
+    }
+
+    /// Config files in the order they are merged, later files override earlier ones.
+    /// Every config directory, starting with the system-wide ones from `$XDG_CONFIG_DIRS`,
+    /// adds its `config.toml` followed by the drop-ins in `config.d` sorted by name.
+    /// A file given on the command line replaces all of them.
+    pub fn files(config_path: Option<PathBuf>) -> Vec<PathBuf> {
         if let Some(config_file) = config_path {
-            let content = read_to_string(config_file)?;
-            Ok(toml::from_str(&content)?)
-        } else if let Some(config_file) = xdg_dirs.find_config_file("config.toml") {
-            let content = read_to_string(config_file)?;
-            Ok(toml::from_str(&content)?)
+            return vec![config_file];
+        }
+
+        let mut files = Vec::new();
+        for dir in Self::dirs() {
+            let config_file = dir.join("config.toml");
+            if config_file.is_file() {
+                files.push(config_file);
+            }
+            if let Ok(entries) = read_dir(dir.join("config.d")) {
+                let mut drop_ins: Vec<PathBuf> = entries
+                    .filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|path| path.extension() == Some("toml".as_ref()) && path.is_file())
+                    .collect();
+                drop_ins.sort();
+                files.extend(drop_ins);
+            }
+        }
+        files
+    }
+
+    /// Config directories of kickoff, system-wide ones first
+    pub fn dirs() -> Vec<PathBuf> {
+        let xdg_dirs = BaseDirectories::with_prefix("kickoff");
+        let mut dirs = xdg_dirs.get_config_dirs();
+        dirs.reverse();
+        dirs.extend(xdg_dirs.get_config_home());
+        dirs
+    }
+
+    /// Reads and deep-merges `files` with the files they include,
+    /// followed by the theme and the `overrides`
+    pub fn load_files(
+        files: &[PathBuf],
+        overrides: &Overrides,
+    ) -> Result<LoadedConfig, Diagnostic> {
+        let mut config_table = toml::Table::new();
+        let mut warnings = Vec::new();
+        let mut read = Vec::new();
+        for file in files {
+            merge_file(
+                file,
+                &mut config_table,
+                &mut warnings,
+                &mut read,
+                &mut Vec::new(),
+            )?;
+        }
+
+        // The files or the overrides choose the theme, but it only provides defaults for both
+        let mut chosen = config_table.clone();
+        overrides.apply(&mut chosen);
+        let mut merged = match chosen.get("theme").and_then(toml::Value::as_str) {
+            Some(theme) => load_theme(theme, &mut warnings, &mut read)?,
+            None => toml::Table::new(),
+        };
+        merge(&mut merged, config_table);
+        overrides.apply(&mut merged);
+        let config = toml::Value::Table(merged)
+            .try_into()
+            .map_err(|e: toml::de::Error| Diagnostic::error(None, e.message().to_string()))?;
+        Ok(LoadedConfig {
+            config,
+            warnings,
+            files: read,
+        })
+    }
+
+    /// Parses a config file, returns the config and warnings about unknown keys.
+    /// `path` is only used to locate errors and warnings.
+    pub fn parse(
+        content: &str,
+        path: Option<&Path>,
+    ) -> Result<(Self, Vec<Diagnostic>), Diagnostic> {
+        let config: Self = toml::from_str(content)
+            .map_err(|e| Diagnostic::error(path, e.message().to_string()).at(content, e.span()))?;
+
+        let mut warnings = Vec::new();
+        // Already parsed by toml, this only fails on invalid documents
+        if let Ok(document) = ImDocument::parse(content) {
+            unknown_keys(document.as_table(), "", &mut |key, table| {
+                let message = if table.is_empty() {
+                    format!("unknown key `{key}`")
+                } else {
+                    format!("unknown key `{key}` in [{table}]")
+                };
+                warnings.push(Diagnostic::warning(path, message).at(content, key.span()));
+            });
+        }
+        Ok((config, warnings))
+    }
+}
+
+/// Writes floats with the digits they were configured with instead of their f64 expansion
+fn serialize_f32<S: Serializer>(value: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(value.to_string().parse().unwrap_or_default())
+}
+
+/// Prefix of environment variables overriding config values, `__` separates tables:
+/// `KICKOFF_COLORS__BACKGROUND` sets `colors.background`
+const ENV_PREFIX: &str = "KICKOFF_";
+
+/// Config values set for a single run by `KICKOFF_*` variables, `--theme` and `--set`,
+/// applied over the config files and the theme
+#[derive(Clone, Debug, Default)]
+pub struct Overrides(Vec<Override>);
+
+#[derive(Clone, Debug)]
+struct Override {
+    path: Vec<String>,
+    value: toml::Value,
+}
+
+impl Overrides {
+    /// Collects the environment variables, `theme` and the `key.path=value` pairs of `set`,
+    /// later ones win
+    pub fn new(theme: Option<&str>, set: &[String]) -> Result<Self, Diagnostic> {
+        let mut variables: Vec<(String, String)> = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        variables.sort();
+
+        let mut overrides = Vec::new();
+        for (name, value) in &variables {
+            let path = name[ENV_PREFIX.len()..].to_lowercase();
+            // Other programs may use the prefix as well
+            if !is_known_path(&path.split("__").collect::<Vec<_>>()) {
+                warn!("{name}: unknown key `{}`, ignored", path.replace("__", "."));
+                continue;
+            }
+            overrides.push(Override::new(name, path.split("__"), value)?);
+        }
+        if let Some(theme) = theme {
+            overrides.push(Override::new(
+                &format!("--theme {theme}"),
+                ["theme"].into_iter(),
+                &toml::Value::String(theme.to_string()).to_string(),
+            )?);
+        }
+        for pair in set {
+            let source = format!("--set {pair}");
+            let Some((path, value)) = pair.split_once('=') else {
+                return Err(Diagnostic::error(
+                    None,
+                    format!("{source}: expected key.path=value"),
+                ));
+            };
+            overrides.push(Override::new(&source, path.trim().split('.'), value)?);
+        }
+        Ok(Self(overrides))
+    }
+
+    fn apply(&self, table: &mut toml::Table) {
+        for Override { path, value } in &self.0 {
+            let (key, tables) = path.split_last().expect("keys are not empty");
+            let mut table = &mut *table;
+            for name in tables {
+                let entry = table
+                    .entry(name.clone())
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+                if !entry.is_table() {
+                    *entry = toml::Value::Table(toml::Table::new());
+                }
+                table = entry.as_table_mut().expect("replaced by a table");
+            }
+            table.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+impl Override {
+    /// Parses `value` as TOML value, anything else is taken as string.
+    /// `source` is where the value was set, for error messages.
+    fn new<'a>(
+        source: &str,
+        path: impl Iterator<Item = &'a str>,
+        value: &str,
+    ) -> Result<Self, Diagnostic> {
+        let error = |message: String| Diagnostic::error(None, format!("{source}: {message}"));
+        let path: Vec<String> = path.map(str::to_string).collect();
+        if path.iter().any(String::is_empty) {
+            return Err(error("empty key".to_string()));
+        }
+
+        if !is_known_path(&path) {
+            return Err(error(format!("unknown key `{}`", path.join("."))));
+        }
+
+        let value = toml::from_str::<toml::Table>(&format!("value = {value}"))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or_else(|| toml::Value::String(value.to_string()));
+        let value = Self { path, value };
+
+        // Check the type on its own, errors of the merged config can't tell where a value came from
+        let mut table = toml::Table::new();
+        Overrides(vec![value.clone()]).apply(&mut table);
+        toml::Value::Table(table)
+            .try_into::<Config>()
+            .map_err(|e| error(e.message().to_string()))?;
+        Ok(value)
+    }
+}
+
+/// Whether the config has the key at `path`, keys in tables with arbitrary keys,
+/// like `aliases`, always exist
+fn is_known_path<S: AsRef<str>>(path: &[S]) -> bool {
+    let mut table = String::new();
+    for name in path {
+        match known_keys(&table) {
+            Some(known) if !known.contains(&name.as_ref()) => return false,
+            None => return true,
+            Some(_) => {}
+        }
+        if !table.is_empty() {
+            table.push('.');
+        }
+        table.push_str(name.as_ref());
+    }
+    true
+}
+
+/// Keys a theme may set, everything else is left to the config
+const THEME_KEYS: [&str; 6] = ["font", "fonts", "font_size", "padding", "colors", "style"];
+
+/// Themes shipped with kickoff, used if no theme file of that name is found
+const BUILTIN_THEMES: [(&str, &str); 4] = [
+    ("one-dark", include_str!("../assets/themes/one-dark.toml")),
+    ("nord", include_str!("../assets/themes/nord.toml")),
+    ("gruvbox", include_str!("../assets/themes/gruvbox.toml")),
+    (
+        "solarized-light",
+        include_str!("../assets/themes/solarized-light.toml"),
+    ),
+];
+
+/// Reads `themes/<name>.toml` from the config or data directories,
+/// falls back to the built-in themes
+fn load_theme(
+    name: &str,
+    warnings: &mut Vec<Diagnostic>,
+    read: &mut Vec<PathBuf>,
+) -> Result<toml::Table, Diagnostic> {
+    let xdg_dirs = BaseDirectories::with_prefix("kickoff");
+    let file = format!("themes/{name}.toml");
+    let (path, content) = match xdg_dirs
+        .find_config_file(&file)
+        .or_else(|| xdg_dirs.find_data_file(&file))
+    {
+        Some(path) => {
+            let content =
+                read_to_string(&path).map_err(|e| Diagnostic::error(Some(&path), e.to_string()))?;
+            (Some(path), content)
+        }
+        None => match BUILTIN_THEMES.iter().find(|(builtin, _)| *builtin == name) {
+            Some((_, content)) => (None, (*content).to_string()),
+            None => return Err(Diagnostic::error(None, format!("theme `{name}` not found"))),
+        },
+    };
+
+    read.extend(path.clone());
+    parse_theme(&content, path.as_deref(), warnings)
+}
+
+/// Parses a theme file, keys that are not part of a theme are dropped with a warning
+fn parse_theme(
+    content: &str,
+    path: Option<&Path>,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<toml::Table, Diagnostic> {
+    let (_, theme_warnings) = Config::parse(content, path)?;
+    warnings.extend(theme_warnings);
+    let mut table: toml::Table =
+        toml::from_str(content).map_err(|e| Diagnostic::error(path, e.message().to_string()))?;
+    if let Ok(document) = ImDocument::parse(content) {
+        for (key, _) in document.as_table().iter() {
+            if THEME_KEYS.contains(&key) || table.remove(key).is_none() {
+                continue;
+            }
+            // Unknown keys were already reported by `Config::parse`
+            if known_keys("").is_some_and(|known| !known.contains(&key)) {
+                continue;
+            }
+            let span = document.as_table().key(key).and_then(Key::span);
+            warnings.push(
+                Diagnostic::warning(path, format!("`{key}` can't be set by a theme"))
+                    .at(content, span),
+            );
+        }
+    }
+    Ok(table)
+}
+
+/// The merged config and what was found on the way
+pub struct LoadedConfig {
+    pub config: Config,
+    pub warnings: Vec<Diagnostic>,
+    /// Every file read, including the included ones
+    pub files: Vec<PathBuf>,
+}
+
+/// Merges the config file at `path` into `merged`, after the files it includes.
+/// `including` holds the files that include it, to detect cycles.
+fn merge_file(
+    path: &Path,
+    merged: &mut toml::Table,
+    warnings: &mut Vec<Diagnostic>,
+    read: &mut Vec<PathBuf>,
+    including: &mut Vec<PathBuf>,
+) -> Result<(), Diagnostic> {
+    let content = read_to_string(path).map_err(|e| Diagnostic::error(Some(path), e.to_string()))?;
+    // Each file is parsed on its own first, to locate errors in it
+    let (config, file_warnings) = Config::parse(&content, Some(path))?;
+    warnings.extend(file_warnings);
+
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if including.contains(&canonical) {
+        return Err(Diagnostic::error(
+            Some(path),
+            "include cycle through this file".to_string(),
+        ));
+    }
+    including.push(canonical);
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    for include in &config.include {
+        let include = dir.join(expand_home(include));
+        if !include.is_file() {
+            return Err(Diagnostic::error(
+                Some(path),
+                format!("included file {} not found", include.display()),
+            ));
+        }
+        merge_file(&include, merged, warnings, read, including)?;
+    }
+    including.pop();
+
+    let mut table: toml::Table = toml::from_str(&content)
+        .map_err(|e| Diagnostic::error(Some(path), e.message().to_string()))?;
+    table.remove("include");
+    merge(merged, table);
+    read.push(path.to_path_buf());
+    Ok(())
+}
+
+/// Replaces a leading `~` by the home directory
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Merges `overlay` into `base`, tables are merged key by key,
+/// all other values, including arrays, are replaced
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Calls `found` with every key of `table` and its subtables the config doesn't know.
+/// `#[serde(default)]` ignores them silently, which hides typos.
+fn unknown_keys(table: &dyn TableLike, path: &str, found: &mut dyn FnMut(&Key, &str)) {
+    let known = known_keys(path);
+    for (name, item) in table.iter() {
+        let Some((key, _)) = table.get_key_value(name) else {
+            continue;
+        };
+        if known.is_some_and(|known| !known.contains(&name)) {
+            found(key, path);
+            continue;
+        }
+
+        let path = if path.is_empty() {
+            name.to_string()
         } else {
-            let config_file: PathBuf = xdg_dirs.place_config_file("config.toml")?;
-            let default = include_bytes!("../assets/default_config.toml");
-            write(config_file, default)?;
-            Ok(toml::from_str(&String::from_utf8_lossy(default))?)
+            format!("{path}.{name}")
+        };
+        if let Some(table) = item.as_table_like() {
+            unknown_keys(table, &path, found);
+        } else if let Some(tables) = item.as_array_of_tables() {
+            for table in tables {
+                unknown_keys(table, &path, found);
+            }
+        } else if let Some(array) = item.as_array() {
+            for table in array.iter().filter_map(Value::as_inline_table) {
+                unknown_keys(table, &path, found);
+            }
         }
     }
 }
 
+/// Byte range of the key at `path` in `table`
+fn key_span(table: &dyn TableLike, path: &[&str]) -> Option<Range<usize>> {
+    let (name, rest) = path.split_first()?;
+    let (key, item) = table.get_key_value(name)?;
+    if rest.is_empty() {
+        key.span()
+    } else {
+        key_span(item.as_table_like()?, rest)
+    }
+}
+
+/// Keys of the config table at `path`, `None` for tables with arbitrary keys
+fn known_keys(path: &str) -> Option<&'static [&'static str]> {
+    Some(match path {
+        "" => fields::<Config>(),
+        "colors" => fields::<ColorConfig>(),
+        "style" => fields::<StyleConfig>(),
+        "history" => fields::<HistoryConfig>(),
+        "keybindings" => fields::<KeybindingsConfig>(),
+        "keybindings.normal" => fields::<NormalKeybindingsConfig>(),
+        "keybindings.custom" => fields::<CustomKeybinding>(),
+        "search" => fields::<SearchConfig>(),
+        "window" => fields::<WindowConfig>(),
+        "launch" => fields::<LaunchConfig>(),
+        _ => return None,
+    })
+}
+
+/// Field names of a struct, taken from its `Deserialize` implementation
+fn fields<T: DeserializeOwned>() -> &'static [&'static str] {
+    struct Probe<'a>(&'a Cell<&'static [&'static str]>);
+
+    impl<'de> Deserializer<'de> for Probe<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0.set(fields);
+            Err(de::Error::custom("fields found"))
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+            identifier ignored_any
+        }
+    }
+
+    let fields = Cell::new(&[][..]);
+    let _ = T::deserialize(Probe(&fields));
+    fields.get()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem in the config, located by file, line and column where possible
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub path: Option<PathBuf>,
+    /// Line and column, both starting at 1
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(path: Option<&Path>, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            path: path.map(Path::to_path_buf),
+            position: None,
+            message,
+        }
+    }
+
+    pub fn warning(path: Option<&Path>, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(path, message)
+        }
+    }
+
+    /// Error at the config key `key`, a dotted path, in the last of `files` that sets it.
+    /// For problems found after the files were merged.
+    pub fn at_key(files: &[PathBuf], key: &str, message: String) -> Self {
+        let path: Vec<&str> = key.split('.').collect();
+        for file in files.iter().rev() {
+            let Ok(content) = read_to_string(file) else {
+                continue;
+            };
+            let Ok(document) = ImDocument::parse(content.as_str()) else {
+                continue;
+            };
+            if let Some(span) = key_span(document.as_table(), &path) {
+                return Self::error(Some(file), message).at(&content, Some(span));
+            }
+        }
+        // Set by `--set` or a `KICKOFF_*` variable
+        Self::error(None, message)
+    }
+
+    /// Locates the diagnostic at the start of the byte range `span` of `content`
+    fn at(mut self, content: &str, span: Option<Range<usize>>) -> Self {
+        self.position = span.map(|span| {
+            let before = &content[..span.start.min(content.len())];
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            (
+                before.matches('\n').count() + 1,
+                before[line_start..].chars().count() + 1,
+            )
+        });
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}:", path.display())?;
+            if let Some((line, column)) = self.position {
+                write!(f, "{line}:{column}:")?;
+            }
+            f.write_str(" ")?;
+        }
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity}: {}", self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HistoryEntry {
     pub name: String,
@@ -266,6 +1050,10 @@ impl History {
         }
     }
 
+    pub fn remove(&mut self, element: &Element) {
+        self.entries.retain(|x| x.name != element.name);
+    }
+
     pub fn save(&self) -> Result<(), std::io::Error> {
         let mut wtr = csv::Writer::from_path(&self.path)?;
         for entry in &self.entries {
@@ -276,3 +1064,179 @@ impl History {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let (_, warnings) =
+            Config::parse(include_str!("../assets/default_config.toml"), None).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+
+        let content = "paddng = 3\n[style]\nborder_width = 2\n  boder = 1\n[aliases]\nfoo = 'bar'\n\n[[keybindings.custom]]\nkey = 'alt+x'\ncommand = 'x'\ncmd = 'x'\n";
+        let (_, warnings) = Config::parse(content, None).unwrap();
+        let warnings: Vec<_> = warnings
+            .iter()
+            .map(|warning| (warning.position, warning.message.as_str()))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                (Some((1, 1)), "unknown key `paddng`"),
+                (Some((4, 3)), "unknown key `boder` in [style]"),
+                (Some((11, 1)), "unknown key `cmd` in [keybindings.custom]"),
+            ]
+        );
+
+        let path = Path::new("config.toml");
+        let err = Config::parse("[colors]\nborder = '#zz'\n", Some(path)).unwrap_err();
+        assert_eq!(err.severity, Severity::Error);
+        assert_eq!(
+            err.to_string(),
+            r##"config.toml:2:10: error: invalid value: string "#zz", expected a hex rgb or rgba color value"##
+        );
+    }
+
+    #[test]
+    fn load_files_test() {
+        let dir = env::temp_dir().join(format!("kickoff-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("config.d")).unwrap();
+        let file = |name: &str, content: &str| {
+            write(dir.join(name), content).unwrap();
+            dir.join(name)
+        };
+        file(
+            "base.toml",
+            "padding = 1\nfonts = ['a', 'b']\n[style]\nborder_width = 2\ncorner_radius = 3\n",
+        );
+        let config = file(
+            "config.toml",
+            "include = ['base.toml']\npadding = 4\n[style]\nborder_width = 5\n",
+        );
+        let drop_in = file(
+            "config.d/host.toml",
+            "fonts = ['c']\n[style]\nline_spacing = 2.0\n",
+        );
+
+        let loaded =
+            Config::load_files(&[config.clone(), drop_in.clone()], &Overrides::default()).unwrap();
+        assert!(loaded.warnings.is_empty());
+        assert_eq!(
+            loaded.files,
+            [dir.join("base.toml"), config.clone(), drop_in]
+        );
+        let merged = loaded.config;
+        assert_eq!(merged.padding, 4);
+        assert_eq!(merged.fonts, ["c"]);
+        assert_eq!(merged.style.border_width, 5);
+        assert_eq!(merged.style.corner_radius, 3);
+        assert_eq!(merged.style.line_spacing, 2.0);
+
+        file("base.toml", "include = ['config.toml']\n");
+        let Err(err) = Config::load_files(&[config], &Overrides::default()) else {
+            panic!("include cycle not detected");
+        };
+        assert_eq!(err.message, "include cycle through this file");
+
+        // Located in the last file setting the key
+        let keys = file(
+            "keys.toml",
+            "[keybindings]\nnav_down = ['Tab']\n\n[[keybindings.custom]]\nkey = 'x'\n",
+        );
+        let other = file("other.toml", "padding = 1\n");
+        let files = [dir.join("base.toml"), keys.clone(), other];
+        let diagnostic = Diagnostic::at_key(&files, "keybindings.nav_down", "x".to_string());
+        assert_eq!(diagnostic.path, Some(keys));
+        assert_eq!(diagnostic.position, Some((2, 1)));
+        let diagnostic = Diagnostic::at_key(&files, "keybindings.custom", "x".to_string());
+        assert_eq!(diagnostic.position, Some((4, 15)));
+        let diagnostic = Diagnostic::at_key(&files, "keybindings.exit", "x".to_string());
+        assert_eq!(diagnostic.path, None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn theme_test() {
+        for (name, content) in BUILTIN_THEMES {
+            let mut warnings = Vec::new();
+            parse_theme(content, None, &mut warnings).unwrap();
+            assert!(warnings.is_empty(), "{name}: {warnings:?}");
+        }
+
+        let mut warnings = Vec::new();
+        let content = "padding = 3\n[colors]\ntext = '#ffffff'\n[keybindings]\nexit = ['q']\n";
+        let theme = parse_theme(content, None, &mut warnings).unwrap();
+        assert_eq!(theme.keys().collect::<Vec<_>>(), ["colors", "padding"]);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].position, Some((4, 2)));
+        assert_eq!(warnings[0].message, "`keybindings` can't be set by a theme");
+
+        let dir = env::temp_dir().join(format!("kickoff-theme-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.toml");
+        write(
+            &config,
+            "theme = 'nord'\npadding = 7\n[style]\nborder_width = 9\n",
+        )
+        .unwrap();
+        let loaded =
+            Config::load_files(std::slice::from_ref(&config), &Overrides::default()).unwrap();
+        // The theme is applied first, then the config files and the overrides
+        assert_eq!(loaded.config.padding, 7);
+        assert_eq!(loaded.config.style.border_width, 9);
+        assert_eq!(loaded.config.style.corner_radius, 8);
+        let set = ["style.border_width=4".to_string()];
+        let overrides = Overrides::new(Some("solarized-light"), &set).unwrap();
+        let loaded = Config::load_files(&[config], &overrides).unwrap();
+        assert_eq!(loaded.config.theme.as_deref(), Some("solarized-light"));
+        assert_eq!(loaded.config.style.corner_radius, 6);
+        assert_eq!(loaded.config.style.border_width, 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overrides_test() {
+        let set = [
+            "padding=5",
+            "font_size=20",
+            "colors.background=#ff000080",
+            "prompt=run: ",
+            "aliases.web=firefox",
+            "keybindings.exit=['q']",
+            "padding=6",
+        ]
+        .map(str::to_string);
+        let overrides = Overrides::new(Some("nord"), &set).unwrap();
+        let mut table = toml::Table::new();
+        overrides.apply(&mut table);
+        let config: Config = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(config.padding, 6);
+        assert_eq!(config.font_size, 20.);
+        assert_eq!(config.colors.background.3, 128);
+        assert_eq!(config.prompt, "run: ");
+        assert_eq!(config.aliases["web"], "firefox");
+        assert_eq!(config.keybindings.exit.len(), 1);
+        assert_eq!(config.theme.as_deref(), Some("nord"));
+
+        let error = |set: &str| {
+            Overrides::new(None, &[set.to_string()])
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error("padding=abc"),
+            r#"error: --set padding=abc: invalid type: string "abc", expected u32"#
+        );
+        assert_eq!(
+            error("style.bordr_width=1"),
+            "error: --set style.bordr_width=1: unknown key `style.bordr_width`"
+        );
+        assert_eq!(
+            error("padding"),
+            "error: --set padding: expected key.path=value"
+        );
+    }
+}
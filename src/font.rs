@@ -1,8 +1,10 @@
 use crate::color::Color;
-use fontdue::layout::{CoordinateSystem, GlyphRasterConfig, Layout, LayoutSettings, TextStyle};
+use crate::config::Diagnostic;
+use fontdue::layout::GlyphRasterConfig;
 use fontdue::Metrics;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ops::Range;
 use std::path::PathBuf;
 
 use tokio::{
@@ -11,46 +13,136 @@ use tokio::{
 };
 
 use fontconfig::Fontconfig;
+use unicode_bidi::BidiInfo;
+use unicode_properties::{GeneralCategory, GeneralCategoryGroup, UnicodeGeneralCategory};
 
 use image::{Pixel, RgbaImage};
 
 pub struct Font {
     fonts: Vec<fontdue::Font>,
-    layout: RefCell<Layout>,
+    /// Raw font files, parsed again by rustybuzz for shaping
+    font_data: Vec<Vec<u8>>,
     size: f32,
     scale: i32,
     glyph_cache: RefCell<HashMap<GlyphRasterConfig, (Metrics, Vec<u8>)>>,
     tab_width: usize,
 }
 
+/// A glyph positioned on a single line, coordinates are in pixel
+#[derive(Debug, Clone)]
+pub struct ShapedGlyph {
+    pub font_index: usize,
+    pub glyph_index: u16,
+    /// Position of the pen before drawing the glyph
+    pub x: f32,
+    pub x_offset: f32,
+    pub y_offset: f32,
+    pub advance: f32,
+}
+
+/// Family names fontconfig maps to one of the installed fonts
+const GENERIC_FAMILIES: [&str; 7] = [
+    "sans-serif",
+    "sans",
+    "serif",
+    "monospace",
+    "mono",
+    "emoji",
+    "system-ui",
+];
+
+/// Checks that fontconfig finds the fonts and fontdue can read them.
+/// Fonts fontconfig replaces by a different one are reported as warnings.
+pub fn check(font_names: &[String]) -> Vec<Diagnostic> {
+    let Some(fc) = Fontconfig::new() else {
+        return vec![Diagnostic::error(
+            None,
+            "couldn't load fontconfig".to_string(),
+        )];
+    };
+    let mut diagnostics = Vec::new();
+    for name in font_names {
+        let Some(font) = fc.find(name, None) else {
+            diagnostics.push(Diagnostic::error(None, format!("font `{name}` not found")));
+            continue;
+        };
+        let readable = std::fs::read(&font.path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| {
+                fontdue::Font::from_bytes(data, fontdue::FontSettings::default())
+                    .map(|_| ())
+                    .map_err(str::to_string)
+            });
+        if let Err(e) = readable {
+            diagnostics.push(Diagnostic::error(
+                None,
+                format!("font `{name}` ({}): {e}", font.path.display()),
+            ));
+        } else if !font.name.to_lowercase().contains(&name.to_lowercase())
+            && !GENERIC_FAMILIES.contains(&name.to_lowercase().as_str())
+        {
+            diagnostics.push(Diagnostic::warning(
+                None,
+                format!("font `{name}` not found, `{}` is used instead", font.name),
+            ));
+        }
+    }
+    diagnostics
+}
+
 impl Font {
     pub async fn new(font_names: Vec<String>, size: f32) -> io::Result<Self> {
+        let mut font_data = Vec::new();
+        for font_path in Self::paths(font_names)? {
+            let mut font_buffer = Vec::new();
+            File::open(font_path.to_str().unwrap())
+                .await?
+                .read_to_end(&mut font_buffer)
+                .await?;
+            font_data.push(font_buffer);
+        }
+
+        Self::from_data(font_data, size)
+    }
+
+    /// Blocking version of `new`, for reloading the config
+    pub fn load(font_names: Vec<String>, size: f32) -> io::Result<Self> {
+        let font_data = Self::paths(font_names)?
+            .into_iter()
+            .map(std::fs::read)
+            .collect::<io::Result<_>>()?;
+        Self::from_data(font_data, size)
+    }
+
+    fn paths(font_names: Vec<String>) -> io::Result<Vec<PathBuf>> {
         let fc = Fontconfig::new().expect("Couldn't load fontconfig");
         let font_names = if font_names.is_empty() {
             vec![String::new()]
         } else {
             font_names
         };
-        let font_paths: Vec<PathBuf> = font_names
+        font_names
             .iter()
-            .map(|name| fc.find(name, None).unwrap().path)
-            .collect();
-        let mut font_data = Vec::new();
+            .map(|name| {
+                fc.find(name, None)
+                    .map(|font| font.path)
+                    .ok_or_else(|| io::Error::other(format!("font `{name}` not found")))
+            })
+            .collect()
+    }
 
-        for font_path in font_paths {
-            let mut font_buffer = Vec::new();
-            File::open(font_path.to_str().unwrap())
-                .await?
-                .read_to_end(&mut font_buffer)
-                .await?;
-            font_data.push(
-                fontdue::Font::from_bytes(font_buffer, fontdue::FontSettings::default()).unwrap(),
-            );
-        }
+    fn from_data(font_data: Vec<Vec<u8>>, size: f32) -> io::Result<Self> {
+        let fonts = font_data
+            .iter()
+            .map(|data| {
+                fontdue::Font::from_bytes(data.as_slice(), fontdue::FontSettings::default())
+                    .map_err(io::Error::other)
+            })
+            .collect::<io::Result<_>>()?;
 
         Ok(Self {
-            fonts: font_data,
-            layout: RefCell::new(Layout::new(CoordinateSystem::PositiveYDown)),
+            fonts,
+            font_data,
             size,
             scale: 1,
             tab_width: 8,
@@ -62,6 +154,31 @@ impl Font {
         self.scale = scale;
     }
 
+    pub const fn scale(&self) -> i32 {
+        self.scale
+    }
+
+    fn px(&self) -> f32 {
+        self.size * self.scale as f32
+    }
+
+    /// Height of a line of text from ascent to descent of the primary font
+    pub fn line_height(&self) -> f32 {
+        let size = self.px();
+        self.fonts
+            .first()
+            .and_then(|font| font.horizontal_line_metrics(size))
+            .map_or(size, |metrics| metrics.ascent - metrics.descent)
+    }
+
+    fn ascent(&self) -> f32 {
+        let size = self.px();
+        self.fonts
+            .first()
+            .and_then(|font| font.horizontal_line_metrics(size))
+            .map_or(size, |metrics| metrics.ascent)
+    }
+
     fn render_glyph(&self, conf: GlyphRasterConfig) -> (Metrics, Vec<u8>) {
         let mut glyph_cache = self.glyph_cache.borrow_mut();
 
@@ -97,6 +214,126 @@ impl Font {
         res
     }
 
+    fn font_for_char(&self, c: char) -> Option<usize> {
+        self.fonts
+            .iter()
+            .position(|font| font.lookup_glyph_index(c) != 0)
+    }
+
+    /// Splits the text into runs that can be shaped with a single font each.
+    /// Marks, joiners and spaces stay with the font of the preceding character if possible,
+    /// so they can be combined with it during shaping.
+    fn split_runs(&self, text: &str) -> Vec<(usize, Range<usize>)> {
+        let mut runs: Vec<(usize, Range<usize>)> = Vec::new();
+        for (idx, c) in text.char_indices() {
+            let current = runs.last().map(|(font_index, _)| *font_index);
+            let attaches = c.general_category_group() == GeneralCategoryGroup::Mark
+                || c.general_category() == GeneralCategory::Format
+                || (c.is_whitespace()
+                    && current.is_some_and(|i| self.fonts[i].lookup_glyph_index(c) != 0));
+
+            let font_index = match current {
+                Some(current) if attaches => current,
+                _ => self
+                    .font_for_char(c)
+                    .unwrap_or_else(|| current.unwrap_or(0)),
+            };
+
+            match runs.last_mut() {
+                Some((current, range)) if *current == font_index => range.end = idx + c.len_utf8(),
+                _ => runs.push((font_index, idx..idx + c.len_utf8())),
+            }
+        }
+
+        runs
+    }
+
+    /// Checks whether the text is mostly right to left, based on the first strong character
+    pub fn is_rtl(text: &str) -> bool {
+        BidiInfo::new(text, None)
+            .paragraphs
+            .first()
+            .is_some_and(|para| para.level.is_rtl())
+    }
+
+    /// Shapes the text into a single line of positioned glyphs in visual order.
+    /// Each paragraph is reordered according to the Unicode Bidirectional Algorithm.
+    pub fn shape(&self, text: &str) -> Vec<ShapedGlyph> {
+        let bidi = BidiInfo::new(text, None);
+        let mut pen = 0.;
+        let mut res = Vec::new();
+        // Parsed lazily and only once per call, `None` until a run needs the font
+        let mut faces: Vec<Option<Option<rustybuzz::Face>>> = vec![None; self.font_data.len()];
+
+        for para in &bidi.paragraphs {
+            let (levels, runs) = bidi.visual_runs(para, para.range.clone());
+            for run in runs {
+                let rtl = levels[run.start].is_rtl();
+                let mut font_runs = self.split_runs(&text[run.clone()]);
+                if rtl {
+                    font_runs.reverse();
+                }
+                for (font_index, range) in font_runs {
+                    let range = run.start + range.start..run.start + range.end;
+                    let face = faces[font_index].get_or_insert_with(|| {
+                        rustybuzz::Face::from_slice(&self.font_data[font_index], 0)
+                    });
+                    if let Some(face) = face {
+                        self.shape_run(&text[range], font_index, face, rtl, &mut pen, &mut res);
+                    }
+                }
+            }
+        }
+
+        res
+    }
+
+    fn shape_run(
+        &self,
+        text: &str,
+        font_index: usize,
+        face: &rustybuzz::Face,
+        rtl: bool,
+        pen: &mut f32,
+        res: &mut Vec<ShapedGlyph>,
+    ) {
+        let units = self.px() / face.units_per_em() as f32;
+
+        let mut buffer = rustybuzz::UnicodeBuffer::new();
+        buffer.push_str(text);
+        buffer.set_direction(if rtl {
+            rustybuzz::Direction::RightToLeft
+        } else {
+            rustybuzz::Direction::LeftToRight
+        });
+        let glyph_buffer = rustybuzz::shape(face, &[], buffer);
+
+        for (info, position) in glyph_buffer
+            .glyph_infos()
+            .iter()
+            .zip(glyph_buffer.glyph_positions())
+        {
+            let advance = position.x_advance as f32 * units;
+            res.push(ShapedGlyph {
+                font_index,
+                glyph_index: info.glyph_id as u16,
+                x: *pen,
+                x_offset: position.x_offset as f32 * units,
+                y_offset: position.y_offset as f32 * units,
+                advance,
+            });
+            *pen += advance;
+        }
+    }
+
+    /// Width of the text in pixel when rendered in a single line
+    pub fn measure(&self, text: &str) -> f32 {
+        self.shape(&Self::replace_tabs(text, self.tab_width))
+            .iter()
+            .map(|glyph| glyph.advance)
+            .sum()
+    }
+
     pub fn render(
         &self,
         text: &str,
@@ -106,51 +343,144 @@ impl Font {
         y_offset: u32,
         max_width: Option<usize>,
     ) -> (u32, u32) {
-        let mut width = 0;
-        let mut current_width = 0.;
-        let mut layout = self.layout.borrow_mut();
-        layout.reset(&LayoutSettings::default());
-
-        for c in Self::replace_tabs(text, self.tab_width).chars() {
-            let mut font_index = 0;
-            for (i, font) in self.fonts.iter().enumerate() {
-                if font.lookup_glyph_index(c) != 0 {
-                    font_index = i;
-                    break;
-                }
-            }
-            layout.append(
-                &self.fonts,
-                &TextStyle::new(&c.to_string(), self.size * self.scale as f32, font_index),
-            );
-        }
+        let px = self.px();
+        let baseline = y_offset as f32 + self.ascent();
+        let mut width = 0.;
 
-        for glyph in layout.glyphs() {
+        for glyph in self.shape(&Self::replace_tabs(text, self.tab_width)) {
+            let (metrics, bitmap) = self.render_glyph(GlyphRasterConfig {
+                glyph_index: glyph.glyph_index,
+                px,
+                font_hash: self.fonts[glyph.font_index].file_hash(),
+            });
             if let Some(max_width) = max_width {
-                if current_width as usize + glyph.width > max_width {
+                let right = glyph.x + glyph.x_offset + metrics.xmin as f32 + metrics.width as f32;
+                if right > max_width as f32 {
                     break;
                 }
             }
-            let (metrics, bitmap) = self.render_glyph(glyph.key);
-            current_width += metrics.advance_width;
+            width = glyph.x + glyph.advance;
+            if metrics.width == 0 {
+                continue;
+            }
+
+            let left = x_offset as f32 + glyph.x + glyph.x_offset + metrics.xmin as f32;
+            let top = baseline - glyph.y_offset - (metrics.height as i32 + metrics.ymin) as f32;
             for (i, alpha) in bitmap.iter().enumerate() {
-                if alpha != &0 && glyph.width > 0 {
-                    let x = glyph.x + x_offset as f32 + (i % glyph.width) as f32;
-                    let y = glyph.y + y_offset as f32 + (i / glyph.width) as f32;
-
-                    match image.get_pixel_mut_checked(x as u32, y as u32) {
-                        Some(pixel) => {
-                            pixel.blend(&image::Rgba([color.0, color.1, color.2, *alpha]));
-                        }
-                        None => continue,
-                    }
+                if alpha == &0 {
+                    continue;
+                }
+                let x = (left + (i % metrics.width) as f32).round();
+                let y = (top + (i / metrics.width) as f32).round();
+                if x < 0. || y < 0. {
+                    continue;
+                }
+
+                if let Some(pixel) = image.get_pixel_mut_checked(x as u32, y as u32) {
+                    pixel.blend(&image::Rgba([color.0, color.1, color.2, *alpha]));
                 }
             }
         }
-        if let Some(glyph) = layout.glyphs().last() {
-            width = glyph.x as usize + glyph.width;
-        }
 
-        (width as u32, layout.height() as u32)
+        (width as u32, self.line_height() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tests need a font with kerning, arabic and hebrew glyphs,
+    /// so they fail if DejaVu Sans isn't installed instead of testing a fallback font
+    fn dejavu_sans() -> Font {
+        let font = Fontconfig::new()
+            .unwrap()
+            .find("DejaVu Sans", None)
+            .filter(|font| font.name == "DejaVu Sans")
+            .expect("DejaVu Sans is required for the font tests");
+        Font::from_data(vec![std::fs::read(font.path).unwrap()], 32.).unwrap()
+    }
+
+    fn glyph_ids(font: &Font, text: &str) -> Vec<u16> {
+        font.shape(text).iter().map(|g| g.glyph_index).collect()
+    }
+
+    #[test]
+    fn kerning_test() {
+        let font = dejavu_sans();
+        assert!(font.measure("AV") < font.measure("A") + font.measure("V"));
+    }
+
+    #[test]
+    fn combining_mark_test() {
+        let font = dejavu_sans();
+        let glyphs = font.shape("e\u{301}");
+        assert!(glyphs.iter().all(|g| g.font_index == glyphs[0].font_index));
+        assert!((font.measure("e\u{301}") - font.measure("e")).abs() < 0.5);
+    }
+
+    #[test]
+    fn arabic_shaping_test() {
+        let font = dejavu_sans();
+        let isolated = [glyph_ids(&font, "س"), glyph_ids(&font, "ل")].concat();
+        let joined = glyph_ids(&font, "سل");
+        assert_eq!(joined.len(), 2);
+        assert!(joined.iter().all(|id| !isolated.contains(id)));
+    }
+
+    #[test]
+    fn bidi_test() {
+        let font = dejavu_sans();
+        assert!(Font::is_rtl("שלום world"));
+        assert!(!Font::is_rtl("world שלום"));
+
+        // Numbers keep their direction, but are placed left of the hebrew text
+        let expected = [
+            glyph_ids(&font, "12"),
+            glyph_ids(&font, "ב"),
+            glyph_ids(&font, "א"),
+        ]
+        .concat();
+        assert_eq!(glyph_ids(&font, "אב12"), expected);
+
+        let expected = [
+            glyph_ids(&font, "ab "),
+            glyph_ids(&font, "ב"),
+            glyph_ids(&font, "א"),
+        ]
+        .concat();
+        assert_eq!(glyph_ids(&font, "ab אב"), expected);
+    }
+
+    #[test]
+    fn render_test() {
+        let font = dejavu_sans();
+        let mut image = RgbaImage::new(400, 60);
+        let (width, _) = font.render(
+            "Kickoff",
+            &Color(255, 255, 255, 255),
+            &mut image,
+            10,
+            10,
+            None,
+        );
+        assert!((width as f32 - font.measure("Kickoff")).abs() < 1.);
+
+        let drawn_columns: Vec<u32> = (0..image.width())
+            .filter(|x| (0..image.height()).any(|y| image.get_pixel(*x, y).0[3] > 0))
+            .collect();
+        assert!(drawn_columns.first().is_some_and(|x| *x >= 10));
+        assert!(drawn_columns.last().is_some_and(|x| *x <= 10 + width));
+
+        let mut truncated = RgbaImage::new(400, 60);
+        font.render(
+            "Kickoff",
+            &Color(255, 255, 255, 255),
+            &mut truncated,
+            10,
+            10,
+            Some(40),
+        );
+        assert!((50..400).all(|x| (0..60).all(|y| truncated.get_pixel(x, y).0[3] == 0)));
     }
 }
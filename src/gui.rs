@@ -1,20 +1,36 @@
-use crate::{keybinds::Keybindings, App};
+use crate::{
+    config::{Config, Diagnostic, Overrides},
+    font::Font,
+    keybinds::{Keybindings, Mode},
+    layout::Layout,
+    watch::ConfigWatcher,
+    App,
+};
 use image::Pixel;
-use log::{debug, error};
+use log::{debug, error, warn};
 use smithay_client_toolkit::{
+    activation::{ActivationHandler, ActivationState, RequestData},
     compositor::{CompositorHandler, CompositorState},
-    delegate_compositor, delegate_keyboard, delegate_layer, delegate_output, delegate_pointer,
-    delegate_registry, delegate_seat, delegate_shm,
+    delegate_activation, delegate_compositor, delegate_keyboard, delegate_layer, delegate_output,
+    delegate_pointer, delegate_registry, delegate_seat, delegate_shm, delegate_touch,
     output::{OutputHandler, OutputState},
     reexports::{
-        calloop::{EventLoop, LoopHandle},
+        calloop::{
+            generic::Generic,
+            timer::{TimeoutAction, Timer},
+            EventLoop, Interest, LoopHandle, Mode as Trigger, PostAction, RegistrationToken,
+        },
         calloop_wayland_source::WaylandSource,
     },
     registry::{ProvidesRegistryState, RegistryState},
     registry_handlers,
     seat::{
-        keyboard::{KeyEvent, KeyboardHandler, Modifiers},
-        pointer::{PointerEvent, PointerEventKind, PointerHandler},
+        keyboard::{KeyEvent, KeyboardHandler, Modifiers, RepeatInfo},
+        pointer::{
+            AxisScroll, PointerEvent, PointerEventKind, PointerHandler, BTN_LEFT, BTN_MIDDLE,
+            BTN_RIGHT,
+        },
+        touch::TouchHandler,
         Capability, SeatHandler, SeatState,
     },
     shell::{
@@ -27,12 +43,15 @@ use smithay_client_toolkit::{
     shm::{slot::SlotPool, Shm, ShmHandler},
 };
 use std::{
+    cmp::Ordering,
     io::{BufWriter, Read, Write},
+    num::NonZeroU32,
+    path::PathBuf,
     time::Duration,
 };
 use wayland_client::{
     globals::registry_queue_init,
-    protocol::{wl_keyboard, wl_output, wl_pointer, wl_seat, wl_shm, wl_surface},
+    protocol::{wl_keyboard, wl_output, wl_pointer, wl_seat, wl_shm, wl_surface, wl_touch},
     Connection, QueueHandle,
 };
 use wl_clipboard_rs::paste::{get_contents, ClipboardType, Error, MimeType, Seat};
@@ -44,36 +63,58 @@ pub enum Action {
     Complete,
     NavUp,
     NavDown,
+    PageUp,
+    PageDown,
+    First,
+    Last,
     Delete,
     DeleteWord,
     Paste,
     Insert(String),
+    /// Shell command template of a custom keybinding
+    Custom(String),
+    NormalMode,
+    InsertMode,
+    RemoveHistory,
+}
+
+impl Action {
+    /// Actions that are repeated while their key is held down
+    const fn is_repeatable(&self) -> bool {
+        matches!(
+            self,
+            Self::Insert(_)
+                | Self::Delete
+                | Self::DeleteWord
+                | Self::NavUp
+                | Self::NavDown
+                | Self::PageUp
+                | Self::PageDown
+        )
+    }
 }
 
-pub fn run(app: App) {
+pub fn run(app: App, keybindings: Keybindings, config_files: Vec<PathBuf>) {
     let conn = Connection::connect_to_env().unwrap();
 
-    let (globals, event_queue) = registry_queue_init(&conn).unwrap();
+    let (globals, mut event_queue) = registry_queue_init(&conn).unwrap();
     let qh = event_queue.handle();
     let mut event_loop: EventLoop<GuiLayer> =
         EventLoop::try_new().expect("Failed to initialize event loop");
-    let loop_handle = event_loop.handle();
-    WaylandSource::new(conn, event_queue)
-        .insert(loop_handle)
-        .unwrap();
 
     let compositor = CompositorState::bind(&globals, &qh).expect("wl_compositor is not available");
     let layer_shell = LayerShell::bind(&globals, &qh).expect("layer shell is not available");
     let shm = Shm::bind(&globals, &qh).expect("wl_shm is not available");
+    let activation = ActivationState::bind(&globals, &qh)
+        .map_err(|_| debug!("xdg_activation_v1 is not available"))
+        .ok();
 
-    let surface = compositor.create_surface(&qh);
-
-    let layer = layer_shell.create_layer_surface(&qh, surface, Layer::Top, Some("kickoff"), None);
-
-    layer.set_anchor(Anchor::all());
-    layer.set_keyboard_interactivity(KeyboardInteractivity::Exclusive);
-
-    layer.commit();
+    let layer = create_layer(&compositor, &layer_shell, &qh, None);
+    let output_name = app
+        .args
+        .output
+        .clone()
+        .or_else(|| app.config.window.output.clone());
 
     let pool = SlotPool::new(256 * 256 * 4, &shm).expect("Failed to create pool");
 
@@ -82,6 +123,9 @@ pub fn run(app: App) {
         seat_state: SeatState::new(&globals, &qh),
         output_state: OutputState::new(&globals, &qh),
         shm,
+        compositor,
+        layer_shell,
+        activation,
 
         exit: false,
         first_configure: true,
@@ -89,31 +133,101 @@ pub fn run(app: App) {
         width: 256,
         height: 256,
         layer,
+        output: None,
         keyboard: None,
         pointer: None,
+        touch: None,
+        touch_point: None,
         scale_factor: 1,
         modifiers: Modifiers::default(),
-        keybindings: Keybindings::from(app.config.keybindings.clone()),
+        // Used until the compositor sends its settings
+        repeat_info: RepeatInfo::Repeat {
+            rate: NonZeroU32::new(25).unwrap(),
+            delay: 600,
+        },
+        key_repeat: None,
+        scroll_accumulator: 0.,
+        seat: None,
+        last_serial: None,
+        pending_launch: None,
+        activation_timeout: None,
+        keybindings,
         app,
         next_action: None,
         loop_handle: event_loop.handle(),
+        config_watcher: ConfigWatcher::new()
+            .map_err(|e| warn!("Unable to watch the config: {e}"))
+            .ok(),
     };
+    gui_layer.watch_config(&config_files);
+    if let Some(fd) = gui_layer.config_watcher.as_ref().and_then(|w| w.fd().ok()) {
+        let source = Generic::new(fd, Interest::READ, Trigger::Level);
+        let result = event_loop.handle().insert_source(source, |_, _, state| {
+            if state
+                .config_watcher
+                .as_ref()
+                .is_some_and(ConfigWatcher::changed)
+            {
+                state.reload_config();
+            }
+            Ok(PostAction::Continue)
+        });
+        if let Err(e) = result {
+            warn!("Unable to watch the config: {e}");
+        }
+    }
+
+    // Receive the list of outputs before the surface gets mapped
+    event_queue.roundtrip(&mut gui_layer).unwrap();
+    if let Some(name) = output_name {
+        match gui_layer.find_output(&name) {
+            Some(output) => gui_layer.recreate_layer(&qh, Some(output)),
+            None => warn!("Output {name} not found, letting the compositor decide"),
+        }
+    }
+    gui_layer.layer.commit();
+
+    WaylandSource::new(conn, event_queue)
+        .insert(event_loop.handle())
+        .unwrap();
 
     loop {
         event_loop
             .dispatch(Duration::from_millis(50), &mut gui_layer)
             .unwrap();
         match &gui_layer.next_action.take() {
+            Some(Action::Exit) if gui_layer.app.close_actions() => {}
             Some(Action::Exit) => gui_layer.exit = true,
             Some(Action::Complete) => gui_layer.app.complete(),
             Some(Action::Delete) => gui_layer.app.delete(),
             Some(Action::DeleteWord) => gui_layer.app.delete_word(),
             Some(Action::NavUp) => gui_layer.app.nav_up(1),
             Some(Action::NavDown) => gui_layer.app.nav_down(1),
+            Some(Action::PageUp) => {
+                let page = gui_layer.layout().max_entries.max(1);
+                gui_layer.app.nav_up(page);
+            }
+            Some(Action::PageDown) => {
+                let page = gui_layer.layout().max_entries.max(1);
+                gui_layer.app.nav_down(page);
+            }
+            Some(Action::First) => gui_layer.app.nav_first(),
+            Some(Action::Last) => gui_layer.app.nav_last(),
             Some(Action::Insert(s)) => gui_layer.app.insert(s),
+            // One launch at a time, kickoff closes once it succeeded
+            Some(Action::Execute | Action::Custom(_)) if gui_layer.app.launching.is_some() => {}
+            Some(action @ (Action::Execute | Action::Custom(_)))
+                if gui_layer.request_activation(&qh, action) => {}
             Some(Action::Execute) => {
-                gui_layer.app.execute();
-                gui_layer.exit = true;
+                gui_layer.exit = gui_layer.app.execute();
+                gui_layer.watch_launch();
+            }
+            Some(Action::NormalMode) => gui_layer.app.mode = Mode::Normal,
+            Some(Action::InsertMode) => gui_layer.app.mode = Mode::Insert,
+            Some(Action::RemoveHistory) => gui_layer.app.remove_history(),
+            Some(Action::Custom(command)) => {
+                gui_layer.exit = gui_layer.app.execute_custom(command);
+                gui_layer.watch_launch();
             }
             Some(Action::Paste) => {
                 let result =
@@ -139,11 +253,27 @@ pub fn run(app: App) {
     }
 }
 
+/// How long a launch waits for the compositor to hand out an activation token
+const ACTIVATION_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Distance in surface coordinates a finger has to move before a tap becomes a swipe
+const TOUCH_SWIPE_THRESHOLD: f64 = 10.;
+
+struct TouchPoint {
+    id: i32,
+    start: (f64, f64),
+    last: (f64, f64),
+    swiped: bool,
+}
+
 struct GuiLayer {
     registry_state: RegistryState,
     seat_state: SeatState,
     output_state: OutputState,
     shm: Shm,
+    compositor: CompositorState,
+    layer_shell: LayerShell,
+    activation: Option<ActivationState>,
 
     exit: bool,
     first_configure: bool,
@@ -151,14 +281,30 @@ struct GuiLayer {
     width: u32,
     height: u32,
     layer: LayerSurface,
+    output: Option<wl_output::WlOutput>,
     keyboard: Option<wl_keyboard::WlKeyboard>,
     pointer: Option<wl_pointer::WlPointer>,
+    touch: Option<wl_touch::WlTouch>,
+    touch_point: Option<TouchPoint>,
     scale_factor: i32,
     modifiers: Modifiers,
+    repeat_info: RepeatInfo,
+    /// Raw code of the held key and the timer repeating its action
+    key_repeat: Option<(u32, RegistrationToken)>,
+    scroll_accumulator: f64,
+    /// Seat and serial of the last input event, the compositor only hands out activation
+    /// tokens for launches that follow user input
+    seat: Option<wl_seat::WlSeat>,
+    last_serial: Option<u32>,
+    /// Launch waiting for its activation token
+    pending_launch: Option<Action>,
+    /// Timer launching without a token when the compositor doesn't answer
+    activation_timeout: Option<RegistrationToken>,
     app: App,
     next_action: Option<Action>,
     keybindings: Keybindings,
-    loop_handle: LoopHandle<'static, GuiLayer>,
+    loop_handle: LoopHandle<'static, Self>,
+    config_watcher: Option<ConfigWatcher>,
 }
 
 impl CompositorHandler for GuiLayer {
@@ -196,19 +342,24 @@ impl CompositorHandler for GuiLayer {
         &mut self,
         _: &Connection,
         _: &QueueHandle<Self>,
-        _: &wl_surface::WlSurface,
-        _: &wl_output::WlOutput,
+        surface: &wl_surface::WlSurface,
+        output: &wl_output::WlOutput,
     ) {
+        if surface == self.layer.wl_surface() {
+            self.output = Some(output.clone());
+        }
     }
 
     fn surface_leave(
         &mut self,
         _: &Connection,
         _: &QueueHandle<Self>,
-        _: &wl_surface::WlSurface,
+        surface: &wl_surface::WlSurface,
         _: &wl_output::WlOutput,
     ) {
-        self.next_action = Some(Action::Exit);
+        if surface == self.layer.wl_surface() {
+            self.next_action = Some(Action::Exit);
+        }
     }
 }
 
@@ -236,15 +387,32 @@ impl OutputHandler for GuiLayer {
     fn output_destroyed(
         &mut self,
         _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
+        qh: &QueueHandle<Self>,
+        output: wl_output::WlOutput,
     ) {
+        if self.output.as_ref() != Some(&output) {
+            return;
+        }
+
+        // The output is still part of the output state at this point
+        let fallback = self.output_state.outputs().find(|o| o != &output);
+        debug!("Output of the layer got destroyed, moving to {fallback:?}");
+        self.recreate_layer(qh, fallback);
+        self.layer.commit();
+
+        // Leave and close events of the old surface might have been received already
+        self.exit = false;
+        if matches!(self.next_action, Some(Action::Exit)) {
+            self.next_action = None;
+        }
     }
 }
 
 impl LayerShellHandler for GuiLayer {
-    fn closed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _layer: &LayerSurface) {
-        self.exit = true;
+    fn closed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, layer: &LayerSurface) {
+        if layer.wl_surface() == self.layer.wl_surface() {
+            self.exit = true;
+        }
     }
 
     fn configure(
@@ -285,23 +453,12 @@ impl SeatHandler for GuiLayer {
         seat: wl_seat::WlSeat,
         capability: Capability,
     ) {
+        self.seat = Some(seat.clone());
         if capability == Capability::Keyboard && self.keyboard.is_none() {
             debug!("Set keyboard capability");
             let keyboard = self
                 .seat_state
-                .get_keyboard_with_repeat(
-                    qh,
-                    &seat,
-                    None,
-                    self.loop_handle.clone(),
-                    Box::new(|state, _wl_kbd, event| {
-                        if let Some(action) = state.keybindings.get(state.modifiers, event.keysym) {
-                            state.next_action = Some(action.clone());
-                        } else if let Some(input) = event.utf8 {
-                            state.next_action = Some(Action::Insert(input));
-                        }
-                    }),
-                )
+                .get_keyboard(qh, &seat, None)
                 .expect("Failed to create keyboard");
             self.keyboard = Some(keyboard);
         }
@@ -314,6 +471,15 @@ impl SeatHandler for GuiLayer {
                 .expect("Failed to create pointer");
             self.pointer = Some(pointer);
         }
+
+        if capability == Capability::Touch && self.touch.is_none() {
+            debug!("Set touch capability");
+            let touch = self
+                .seat_state
+                .get_touch(qh, &seat)
+                .expect("Failed to create touch");
+            self.touch = Some(touch);
+        }
     }
 
     fn remove_capability(
@@ -332,6 +498,12 @@ impl SeatHandler for GuiLayer {
             debug!("Unset pointer capability");
             self.pointer.take().unwrap().release();
         }
+
+        if capability == Capability::Touch && self.touch.is_some() {
+            debug!("Unset touch capability");
+            self.touch.take().unwrap().release();
+            self.touch_point = None;
+        }
     }
 
     fn remove_seat(&mut self, _: &Connection, _: &QueueHandle<Self>, _: wl_seat::WlSeat) {}
@@ -358,6 +530,7 @@ impl KeyboardHandler for GuiLayer {
         _: &wl_surface::WlSurface,
         _: u32,
     ) {
+        self.stop_key_repeat();
         self.next_action = Some(Action::Exit);
     }
 
@@ -366,14 +539,24 @@ impl KeyboardHandler for GuiLayer {
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
         _: &wl_keyboard::WlKeyboard,
-        _: u32,
+        serial: u32,
         event: KeyEvent,
     ) {
         debug!("Key press: {event:?}");
-        if let Some(action) = self.keybindings.get(self.modifiers, event.keysym) {
-            self.next_action = Some(action.clone());
-        } else if let Some(input) = event.utf8 {
-            self.next_action = Some(Action::Insert(input));
+        self.last_serial = Some(serial);
+        let mode = self.app.mode;
+        let action = match self.keybindings.get(mode, self.modifiers, event.keysym) {
+            Some(action) => Some(action),
+            None if mode == Mode::Insert => event.utf8.map(Action::Insert),
+            None => None,
+        };
+
+        self.stop_key_repeat();
+        if let Some(action) = action {
+            if action.is_repeatable() {
+                self.start_key_repeat(event.raw_code, action.clone());
+            }
+            self.next_action = Some(action);
         }
     }
 
@@ -386,6 +569,27 @@ impl KeyboardHandler for GuiLayer {
         event: KeyEvent,
     ) {
         debug!("Key release: {event:?}");
+        if self
+            .key_repeat
+            .as_ref()
+            .is_some_and(|(key, _)| *key == event.raw_code)
+        {
+            self.stop_key_repeat();
+        }
+    }
+
+    fn update_repeat_info(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_keyboard::WlKeyboard,
+        info: RepeatInfo,
+    ) {
+        debug!("Update repeat info: {info:?}");
+        self.repeat_info = info;
+        if matches!(info, RepeatInfo::Disable) {
+            self.stop_key_repeat();
+        }
     }
 
     fn update_modifiers(
@@ -410,38 +614,405 @@ impl PointerHandler for GuiLayer {
         _pointer: &wl_pointer::WlPointer,
         events: &[PointerEvent],
     ) {
-        use PointerEventKind::Press;
+        use PointerEventKind::{Axis, Motion, Press};
         for event in events {
             // Ignore events for other surfaces
             if &event.surface != self.layer.wl_surface() {
                 continue;
             }
 
-            if let Press { button: 274, .. } = event.kind {
-                let result =
-                    get_contents(ClipboardType::Primary, Seat::Unspecified, MimeType::Text);
-                match result {
-                    Ok((mut pipe, _)) => {
-                        let mut contents = vec![];
-                        pipe.read_to_end(&mut contents).unwrap();
-                        let input = String::from_utf8(contents).unwrap();
-                        self.next_action = Some(Action::Insert(input));
+            if let Press { serial, .. } = event.kind {
+                self.last_serial = Some(serial);
+            }
+            match event.kind {
+                Motion { .. } => {
+                    if let Some(index) = self.entry_at(event.position) {
+                        self.app.select(index);
+                    }
+                }
+                Press {
+                    button: BTN_LEFT, ..
+                } => {
+                    if let Some(index) = self.entry_at(event.position) {
+                        self.app.select(index);
+                        self.next_action = Some(Action::Execute);
+                    }
+                }
+                // Offers the custom commands for the entry, a second click closes them
+                Press {
+                    button: BTN_RIGHT, ..
+                } if self.app.close_actions() => {}
+                Press {
+                    button: BTN_RIGHT, ..
+                } => {
+                    if let Some(index) = self.entry_at(event.position) {
+                        self.app.open_actions(index);
+                    }
+                }
+                Press {
+                    button: BTN_MIDDLE, ..
+                } => {
+                    let result =
+                        get_contents(ClipboardType::Primary, Seat::Unspecified, MimeType::Text);
+                    match result {
+                        Ok((mut pipe, _)) => {
+                            let mut contents = vec![];
+                            pipe.read_to_end(&mut contents).unwrap();
+                            let input = String::from_utf8(contents).unwrap();
+                            self.next_action = Some(Action::Insert(input));
+                        }
+                        Err(Error::NoSeats | Error::ClipboardEmpty | Error::NoMimeType) => {}
+                        Err(e) => error!("{e}"),
                     }
-                    Err(Error::NoSeats | Error::ClipboardEmpty | Error::NoMimeType) => {}
-                    Err(e) => error!("{e}"),
                 }
+                Axis { vertical, .. } => self.scroll(vertical),
+                _ => {}
             }
         }
     }
 }
 
+impl TouchHandler for GuiLayer {
+    fn down(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_touch::WlTouch,
+        serial: u32,
+        _time: u32,
+        surface: wl_surface::WlSurface,
+        id: i32,
+        position: (f64, f64),
+    ) {
+        self.last_serial = Some(serial);
+        // Only follow the first finger
+        if &surface != self.layer.wl_surface() || self.touch_point.is_some() {
+            return;
+        }
+        self.scroll_accumulator = 0.;
+        self.touch_point = Some(TouchPoint {
+            id,
+            start: position,
+            last: position,
+            swiped: false,
+        });
+    }
+
+    fn up(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_touch::WlTouch,
+        _serial: u32,
+        _time: u32,
+        id: i32,
+    ) {
+        if self.touch_point.as_ref().map(|point| point.id) != Some(id) {
+            return;
+        }
+        let Some(point) = self.touch_point.take() else {
+            return;
+        };
+        if point.swiped {
+            return;
+        }
+
+        // A tap launches the result below it, tapping outside of query and results closes kickoff
+        if let Some(index) = self.entry_at(point.start) {
+            self.app.select(index);
+            self.next_action = Some(Action::Execute);
+        } else {
+            let (x, y) = self.to_buffer(point.start);
+            if !self.layout().query_rect().contains(x, y) {
+                self.next_action = Some(Action::Exit);
+            }
+        }
+    }
+
+    fn motion(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_touch::WlTouch,
+        _time: u32,
+        id: i32,
+        position: (f64, f64),
+    ) {
+        let Some(point) = self.touch_point.as_mut().filter(|point| point.id == id) else {
+            return;
+        };
+        if (position.1 - point.start.1).abs() > TOUCH_SWIPE_THRESHOLD {
+            point.swiped = true;
+        }
+        if !point.swiped {
+            return;
+        }
+
+        // The list follows the finger, so swiping up reveals later results
+        let distance = point.last.1 - position.1;
+        point.last = position;
+        self.scroll(AxisScroll {
+            absolute: distance,
+            ..AxisScroll::default()
+        });
+    }
+
+    fn shape(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_touch::WlTouch,
+        _: i32,
+        _: f64,
+        _: f64,
+    ) {
+    }
+
+    fn orientation(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_touch::WlTouch,
+        _: i32,
+        _: f64,
+    ) {
+    }
+
+    fn cancel(&mut self, _: &Connection, _: &QueueHandle<Self>, _: &wl_touch::WlTouch) {
+        self.touch_point = None;
+    }
+}
+
 impl ShmHandler for GuiLayer {
     fn shm_state(&mut self) -> &mut Shm {
         &mut self.shm
     }
 }
 
+fn create_layer(
+    compositor: &CompositorState,
+    layer_shell: &LayerShell,
+    qh: &QueueHandle<GuiLayer>,
+    output: Option<&wl_output::WlOutput>,
+) -> LayerSurface {
+    let surface = compositor.create_surface(qh);
+    let layer = layer_shell.create_layer_surface(qh, surface, Layer::Top, Some("kickoff"), output);
+
+    layer.set_anchor(Anchor::all());
+    layer.set_keyboard_interactivity(KeyboardInteractivity::Exclusive);
+
+    layer
+}
+
 impl GuiLayer {
+    /// Finds an output by its name (i.e. `DP-1`) or a part of its description
+    fn find_output(&self, name: &str) -> Option<wl_output::WlOutput> {
+        self.output_state.outputs().find(|output| {
+            self.output_state.info(output).is_some_and(|info| {
+                info.name.as_deref() == Some(name)
+                    || info
+                        .description
+                        .is_some_and(|description| description.contains(name))
+            })
+        })
+    }
+
+    /// Replaces the layer surface with a new one on the given output,
+    /// the new layer still needs to be commited
+    fn recreate_layer(&mut self, qh: &QueueHandle<Self>, output: Option<wl_output::WlOutput>) {
+        self.layer = create_layer(&self.compositor, &self.layer_shell, qh, output.as_ref());
+        if self.scale_factor != 1 {
+            self.layer
+                .set_buffer_scale(self.scale_factor as u32)
+                .unwrap();
+        }
+        self.output = output;
+        self.first_configure = true;
+    }
+
+    /// Repeats the action of a held key with the delay and rate given by the compositor
+    fn start_key_repeat(&mut self, key: u32, action: Action) {
+        let RepeatInfo::Repeat { rate, delay } = self.repeat_info else {
+            return;
+        };
+        let interval = Duration::from_micros(1_000_000 / u64::from(rate.get()));
+        let timer = Timer::from_duration(Duration::from_millis(u64::from(delay)));
+        match self.loop_handle.insert_source(timer, move |_, (), state| {
+            state.next_action = Some(action.clone());
+            TimeoutAction::ToDuration(interval)
+        }) {
+            Ok(token) => self.key_repeat = Some((key, token)),
+            Err(e) => error!("Failed to start key repeat: {e}"),
+        }
+    }
+
+    /// Asks the compositor for an activation token for the launched program and postpones
+    /// the launch until it arrives. Returns whether the launch was postponed.
+    fn request_activation(&mut self, qh: &QueueHandle<Self>, action: &Action) -> bool {
+        let Some(activation) = &self.activation else {
+            return false;
+        };
+        if self.app.args.stdout || self.app.activation_token.is_some() {
+            return false;
+        }
+        if self.pending_launch.is_some() {
+            return true;
+        }
+
+        activation.request_token(
+            qh,
+            RequestData {
+                app_id: Some("kickoff".to_string()),
+                seat_and_serial: self.seat.clone().zip(self.last_serial),
+                surface: Some(self.layer.wl_surface().clone()),
+            },
+        );
+        self.pending_launch = Some(action.clone());
+        // Don't keep the user waiting on compositors that never answer
+        let timer = Timer::from_duration(ACTIVATION_TIMEOUT);
+        match self.loop_handle.insert_source(timer, |_, (), state| {
+            // Dropped by returning, not by `finish_activation`
+            state.activation_timeout = None;
+            warn!("No activation token received, launching without one");
+            state.finish_activation(String::new());
+            TimeoutAction::Drop
+        }) {
+            Ok(token) => self.activation_timeout = Some(token),
+            Err(e) => error!("Failed to start activation timeout: {e}"),
+        }
+        true
+    }
+
+    /// Resumes the launch postponed by `request_activation`, an empty token is not passed on
+    fn finish_activation(&mut self, token: String) {
+        if let Some(timeout) = self.activation_timeout.take() {
+            self.loop_handle.remove(timeout);
+        }
+        if let Some(action) = self.pending_launch.take() {
+            self.app.activation_token = Some(token);
+            self.next_action = Some(action);
+        }
+    }
+
+    /// Waits for the result of a launch in the event loop, so kickoff stays responsive
+    /// while the program is watched for an early exit
+    fn watch_launch(&mut self) {
+        let Some((_, launched)) = &self.app.launching else {
+            return;
+        };
+        let result = launched.fd().map_err(|e| e.to_string()).and_then(|fd| {
+            let source = Generic::new(fd, Interest::READ, Trigger::Level);
+            self.loop_handle
+                .insert_source(source, |_, _, state| match state.app.finish_launch() {
+                    Some(done) => {
+                        state.exit = done;
+                        Ok(PostAction::Remove)
+                    }
+                    None => Ok(PostAction::Continue),
+                })
+                .map_err(|e| e.to_string())
+        });
+        if let Err(e) = result {
+            warn!("Unable to watch the launch, waiting for it: {e}");
+            self.exit = self.app.wait_launch();
+        }
+    }
+
+    /// Watches the config files, the ones that may be created and the drop-in directories
+    fn watch_config(&mut self, files: &[PathBuf]) {
+        let mut files = files.to_vec();
+        let mut drop_in_dirs = Vec::new();
+        if self.app.args.config.is_none() {
+            for dir in Config::dirs() {
+                files.push(dir.join("config.toml"));
+                drop_in_dirs.push(dir.join("config.d"));
+            }
+        }
+        if let Some(watcher) = &mut self.config_watcher {
+            watcher.watch(&files, &drop_in_dirs);
+        }
+    }
+
+    /// Applies changed config files, an invalid config is shown inline and the current one kept
+    fn reload_config(&mut self) {
+        debug!("Reloading config");
+        let args = &self.app.args;
+        let result = Overrides::new(args.theme.as_deref(), &args.set)
+            .and_then(|overrides| {
+                Config::load_files(&Config::files(args.config.clone()), &overrides)
+            })
+            .and_then(|loaded| {
+                let keybindings = Keybindings::try_from(loaded.config.keybindings.clone())
+                    .map_err(|e| Diagnostic::at_key(&loaded.files, e.key(), e.to_string()))?;
+                let font = Font::load(loaded.config.font_names(), loaded.config.font_size)
+                    .map_err(|e| Diagnostic::error(None, e.to_string()))?;
+                Ok((loaded, keybindings, font))
+            });
+        match result {
+            Ok((loaded, keybindings, font)) => {
+                for warning in &loaded.warnings {
+                    warn!("{warning}");
+                }
+                self.watch_config(&loaded.files);
+                self.keybindings = keybindings;
+                self.app.reload(loaded.config, font);
+            }
+            Err(e) => {
+                let message = format!("Config not reloaded: {e}");
+                error!("{message}");
+                self.app.reload_error = Some(message);
+            }
+        }
+    }
+
+    fn stop_key_repeat(&mut self) {
+        if let Some((_, token)) = self.key_repeat.take() {
+            self.loop_handle.remove(token);
+        }
+    }
+
+    /// Layout of the current frame in buffer pixels
+    fn layout(&mut self) -> Layout {
+        let scale = self.scale_factor;
+        self.app
+            .layout(self.width * scale as u32, self.height * scale as u32, scale)
+    }
+
+    /// Converts a position in surface coordinates to buffer pixels
+    fn to_buffer(&self, position: (f64, f64)) -> (f32, f32) {
+        let scale = f64::from(self.scale_factor);
+        ((position.0 * scale) as f32, (position.1 * scale) as f32)
+    }
+
+    /// Index of the result at a position in surface coordinates
+    fn entry_at(&mut self, position: (f64, f64)) -> Option<usize> {
+        let (x, y) = self.to_buffer(position);
+        self.layout().entry_at(x, y)
+    }
+
+    /// Moves the selection by one result per wheel step or per row height of continuous scrolling
+    fn scroll(&mut self, scroll: AxisScroll) {
+        let steps = if scroll.discrete == 0 {
+            let step = f64::from(self.layout().entry_height()) / f64::from(self.scale_factor);
+            self.scroll_accumulator += scroll.absolute;
+            let steps = (self.scroll_accumulator / step).trunc();
+            self.scroll_accumulator -= steps * step;
+            steps as i32
+        } else {
+            self.scroll_accumulator = 0.;
+            scroll.discrete
+        };
+        if scroll.stop {
+            self.scroll_accumulator = 0.;
+        }
+
+        match steps.cmp(&0) {
+            Ordering::Greater => self.app.nav_down(steps.unsigned_abs() as usize),
+            Ordering::Less => self.app.nav_up(steps.unsigned_abs() as usize),
+            Ordering::Equal => {}
+        }
+    }
+
     pub fn draw(&mut self, qh: &QueueHandle<Self>) {
         let width = self.width * self.scale_factor as u32;
         let height = self.height * self.scale_factor as u32;
@@ -493,11 +1064,23 @@ delegate_shm!(GuiLayer);
 delegate_seat!(GuiLayer);
 delegate_keyboard!(GuiLayer);
 delegate_pointer!(GuiLayer);
+delegate_touch!(GuiLayer);
+
+delegate_activation!(GuiLayer);
 
 delegate_layer!(GuiLayer);
 
 delegate_registry!(GuiLayer);
 
+impl ActivationHandler for GuiLayer {
+    type RequestData = RequestData;
+
+    fn new_token(&mut self, token: String, _data: &Self::RequestData) {
+        debug!("Received activation token");
+        self.finish_activation(token);
+    }
+}
+
 impl ProvidesRegistryState for GuiLayer {
     fn registry(&mut self) -> &mut RegistryState {
         &mut self.registry_state
@@ -1,34 +1,120 @@
 use crate::gui::Action;
 use serde::de::{self, Visitor};
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use smithay_client_toolkit::seat::keyboard::{Keysym, Modifiers as ModifiersState};
 use std::collections::HashMap;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use x11_keysymdef::lookup_by_name;
+use std::time::{Duration, Instant};
+use x11_keysymdef::{lookup_by_keysym, lookup_by_name};
 
-use crate::config::KeybindingsConfig;
+use crate::config::{CustomKeybinding, KeybindingsConfig, NormalKeybindingsConfig};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Typed text is added to the query
+    Insert,
+    /// Keys only trigger actions, see `[keybindings.normal]`
+    Normal,
+}
 
 pub struct Keybindings {
-    inner: HashMap<KeyCombo, Action>,
+    /// Bindings of insert mode, together with the config key they come from
+    inner: HashMap<KeyCombo, (String, Action)>,
+    normal: HashMap<Vec<KeyCombo>, (String, Action)>,
+    /// Keys of a normal mode sequence typed so far
+    pending: Vec<KeyCombo>,
+    last_key: Instant,
+    sequence_timeout: Duration,
+}
+
+/// Keys bound to two different actions, or a sequence that can't be typed
+/// since one of its beginnings is bound on its own
+#[derive(Debug)]
+pub struct KeybindingConflict {
+    /// Config key of the binding that causes the conflict
+    key: String,
+    keys: String,
+    action: String,
+    other_keys: String,
+    other_action: String,
 }
 
-impl From<KeybindingsConfig> for Keybindings {
-    fn from(config: KeybindingsConfig) -> Self {
+impl fmt::Display for KeybindingConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.keys == self.other_keys {
+            write!(
+                f,
+                "`{}` is bound to both {} and {}",
+                self.keys, self.action, self.other_action
+            )
+        } else {
+            write!(
+                f,
+                "`{}` of {} hides `{}` of {}",
+                self.keys, self.action, self.other_keys, self.other_action
+            )
+        }
+    }
+}
+
+impl std::error::Error for KeybindingConflict {}
+
+impl KeybindingConflict {
+    /// Dotted path of the config key to blame, like `keybindings.normal.first`
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+/// Config key of the binding called `name`, custom ones are named after their command
+fn config_key(name: &str) -> String {
+    let key = name.split_once(' ').map_or(name, |(key, _)| key);
+    format!("keybindings.{key}")
+}
+
+impl TryFrom<KeybindingsConfig> for Keybindings {
+    type Error = KeybindingConflict;
+
+    fn try_from(config: KeybindingsConfig) -> Result<Self, Self::Error> {
         let mut res = Self {
             inner: HashMap::new(),
+            normal: HashMap::new(),
+            pending: Vec::new(),
+            last_key: Instant::now(),
+            sequence_timeout: Duration::from_millis(config.sequence_timeout),
         };
 
-        res.add_key_combos(&Action::Complete, &config.complete);
-        res.add_key_combos(&Action::Execute, &config.execute);
-        res.add_key_combos(&Action::Exit, &config.exit);
-        res.add_key_combos(&Action::Delete, &config.delete);
-        res.add_key_combos(&Action::DeleteWord, &config.delete_word);
-        res.add_key_combos(&Action::NavUp, &config.nav_up);
-        res.add_key_combos(&Action::NavDown, &config.nav_down);
-        res.add_key_combos(&Action::Paste, &config.paste);
+        res.add_key_combos("complete", &Action::Complete, &config.complete)?;
+        res.add_key_combos("execute", &Action::Execute, &config.execute)?;
+        if config.modal {
+            // Leave insert mode like in vim, exit is bound in normal mode instead
+            res.add_key_combos("exit", &Action::NormalMode, &config.exit)?;
+        } else {
+            res.add_key_combos("exit", &Action::Exit, &config.exit)?;
+        }
+        res.add_key_combos("delete", &Action::Delete, &config.delete)?;
+        res.add_key_combos("delete_word", &Action::DeleteWord, &config.delete_word)?;
+        res.add_key_combos("nav_up", &Action::NavUp, &config.nav_up)?;
+        res.add_key_combos("nav_down", &Action::NavDown, &config.nav_down)?;
+        res.add_key_combos("page_up", &Action::PageUp, &config.page_up)?;
+        res.add_key_combos("page_down", &Action::PageDown, &config.page_down)?;
+        res.add_key_combos("first", &Action::First, &config.first)?;
+        res.add_key_combos("last", &Action::Last, &config.last)?;
+        res.add_key_combos("paste", &Action::Paste, &config.paste)?;
+
+        for custom in &config.custom {
+            let name = format!("custom `{}`", custom.command);
+            let action = Action::Custom(custom.command.clone());
+            res.add_key_combos(&name, &action, std::slice::from_ref(&custom.key))?;
+        }
+
+        // Normal mode is only reachable in modal configs
+        if config.modal {
+            res.add_normal_sequences(config.normal, &config.custom)?;
+        }
 
-        res
+        Ok(res)
     }
 }
 
@@ -67,26 +153,247 @@ pub struct KeyCombo {
 }
 
 impl Keybindings {
-    pub fn get(&self, modifiers: ModifiersState, keysym: Keysym) -> Option<&Action> {
-        self.inner.get(&KeyCombo {
-            modifiers: Modifiers(modifiers),
-            key: keysym,
-        })
+    pub fn get(&mut self, mode: Mode, modifiers: ModifiersState, keysym: Keysym) -> Option<Action> {
+        let combo = KeyCombo::new(Modifiers(modifiers), keysym);
+        match mode {
+            Mode::Insert => self.inner.get(&combo).map(|(_, action)| action.clone()),
+            Mode::Normal if keysym.is_modifier_key() => None,
+            Mode::Normal => self.get_sequence(combo),
+        }
+    }
+
+    /// Adds `combo` to the pending sequence and returns its action once the sequence is complete.
+    /// Unknown sequences start over with the latest key.
+    fn get_sequence(&mut self, combo: KeyCombo) -> Option<Action> {
+        if self.last_key.elapsed() > self.sequence_timeout {
+            self.pending.clear();
+        }
+        self.last_key = Instant::now();
+        self.pending.push(combo.clone());
+
+        loop {
+            if let Some((_, action)) = self.normal.get(&self.pending) {
+                self.pending.clear();
+                return Some(action.clone());
+            }
+            if self.normal.keys().any(|seq| seq.starts_with(&self.pending)) {
+                return None;
+            }
+            if self.pending.len() == 1 {
+                self.pending.clear();
+                return None;
+            }
+            self.pending = vec![combo.clone()];
+        }
     }
 
-    fn add_key_combos(&mut self, action: &Action, key_combos: &[KeyCombo]) {
+    fn add_normal_sequences(
+        &mut self,
+        normal: NormalKeybindingsConfig,
+        custom: &[CustomKeybinding],
+    ) -> Result<(), KeybindingConflict> {
+        self.add_sequences("normal.complete", &Action::Complete, &normal.complete)?;
+        self.add_sequences("normal.execute", &Action::Execute, &normal.execute)?;
+        self.add_sequences("normal.exit", &Action::Exit, &normal.exit)?;
+        self.add_sequences("normal.nav_up", &Action::NavUp, &normal.nav_up)?;
+        self.add_sequences("normal.nav_down", &Action::NavDown, &normal.nav_down)?;
+        self.add_sequences("normal.page_up", &Action::PageUp, &normal.page_up)?;
+        self.add_sequences("normal.page_down", &Action::PageDown, &normal.page_down)?;
+        self.add_sequences("normal.first", &Action::First, &normal.first)?;
+        self.add_sequences("normal.last", &Action::Last, &normal.last)?;
+        self.add_sequences("normal.paste", &Action::Paste, &normal.paste)?;
+        self.add_sequences("normal.insert", &Action::InsertMode, &normal.insert)?;
+        self.add_sequences(
+            "normal.remove_history",
+            &Action::RemoveHistory,
+            &normal.remove_history,
+        )?;
+
+        for custom in custom {
+            let name = format!("custom `{}`", custom.command);
+            let action = Action::Custom(custom.command.clone());
+            self.add_sequences(&name, &action, &[custom.key.clone().into()])?;
+        }
+        self.check_hidden_sequences()
+    }
+
+    fn add_key_combos(
+        &mut self,
+        name: &str,
+        action: &Action,
+        key_combos: &[KeyCombo],
+    ) -> Result<(), KeybindingConflict> {
         for entry in key_combos {
-            self.inner.insert(entry.clone(), action.clone());
+            if let Some((other, _)) = self.inner.get(entry).filter(|(other, _)| other != name) {
+                return Err(KeybindingConflict {
+                    key: config_key(name),
+                    keys: entry.to_string(),
+                    action: format!("`{other}`"),
+                    other_keys: entry.to_string(),
+                    other_action: format!("`{name}`"),
+                });
+            }
+            self.inner
+                .insert(entry.clone(), (name.to_string(), action.clone()));
+        }
+        Ok(())
+    }
+
+    fn add_sequences(
+        &mut self,
+        name: &str,
+        action: &Action,
+        sequences: &[KeySequence],
+    ) -> Result<(), KeybindingConflict> {
+        for sequence in sequences {
+            if let Some((other, _)) = self
+                .normal
+                .get(&sequence.0)
+                .filter(|(other, _)| other != name)
+            {
+                return Err(KeybindingConflict {
+                    key: config_key(name),
+                    keys: sequence.to_string(),
+                    action: format!("`{other}`"),
+                    other_keys: sequence.to_string(),
+                    other_action: format!("`{name}`"),
+                });
+            }
+            self.normal
+                .insert(sequence.0.clone(), (name.to_string(), action.clone()));
         }
+        Ok(())
+    }
+
+    /// Sequences are triggered as soon as they are complete, so `g` would hide `g g`
+    fn check_hidden_sequences(&self) -> Result<(), KeybindingConflict> {
+        for (sequence, (name, _)) in &self.normal {
+            for (other_sequence, (other_name, _)) in &self.normal {
+                if other_sequence.len() > sequence.len() && other_sequence.starts_with(sequence) {
+                    return Err(KeybindingConflict {
+                        key: config_key(name),
+                        keys: KeySequence(sequence.clone()).to_string(),
+                        action: format!("`{name}`"),
+                        other_keys: KeySequence(other_sequence.clone()).to_string(),
+                        other_action: format!("`{other_name}`"),
+                    });
+                }
+            }
+        }
+        Ok(())
     }
 }
 
 impl KeyCombo {
-    pub const fn new(modifiers: Modifiers, key: Keysym) -> Self {
+    /// Shifted letters are stored with their unshifted keysym and the shift modifier,
+    /// so `shift+Tab` matches `ISO_Left_Tab` and `ctrl+V` matches `ctrl+shift+v`.
+    /// Other symbols already include shift and are stored without it, so `question`
+    /// matches the `shift+question` reported by the compositor.
+    pub fn new(modifiers: Modifiers, key: Keysym) -> Self {
+        let mut modifiers = modifiers;
+        let symbol = key.key_char().filter(|c| {
+            !c.is_uppercase() && !c.is_lowercase() && !c.is_whitespace() && !c.is_control()
+        });
+        if symbol.is_some() {
+            modifiers.0.shift = false;
+        }
+        let lowercase = key.key_char().filter(|c| c.is_uppercase()).and_then(|c| {
+            let mut lower = c.to_lowercase();
+            match (lower.next(), lower.next()) {
+                (Some(lower), None) => Some(lower),
+                _ => None,
+            }
+        });
+        let key = if key == Keysym::ISO_Left_Tab {
+            modifiers.0.shift = true;
+            Keysym::Tab
+        } else if let Some(lowercase) = lowercase {
+            modifiers.0.shift = true;
+            Keysym::from_char(lowercase)
+        } else {
+            key
+        };
         Self { modifiers, key }
     }
 }
 
+impl fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let modifiers = &self.modifiers.0;
+        for (name, active) in [
+            ("ctrl", modifiers.ctrl),
+            ("alt", modifiers.alt),
+            ("shift", modifiers.shift),
+            ("logo", modifiers.logo),
+        ] {
+            if active {
+                write!(f, "{name}+")?;
+            }
+        }
+        match lookup_by_keysym(self.key.raw()) {
+            Some(record) => f.write_str(record.names[0]),
+            None => write!(f, "{:#x}", self.key.raw()),
+        }
+    }
+}
+
+/// Keys pressed one after another, separated by spaces in the config: `"g g"`
+#[derive(Clone, Debug)]
+pub struct KeySequence(Vec<KeyCombo>);
+
+impl From<KeyCombo> for KeySequence {
+    fn from(combo: KeyCombo) -> Self {
+        Self(vec![combo])
+    }
+}
+
+impl From<Vec<KeyCombo>> for KeySequence {
+    fn from(combos: Vec<KeyCombo>) -> Self {
+        Self(combos)
+    }
+}
+
+impl fmt::Display for KeySequence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, combo) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{combo}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for KeySequence {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeySequence {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        let combos = value
+            .split_whitespace()
+            .map(|combo| KeyComboVisitor.visit_str(combo))
+            .collect::<Result<Vec<_>, D::Error>>()?;
+        if combos.is_empty() {
+            return Err(de::Error::custom("empty key sequence"));
+        }
+        Ok(Self(combos))
+    }
+}
+
+impl Serialize for KeyCombo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl<'de> Deserialize<'de> for KeyCombo {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -109,25 +416,141 @@ impl Visitor<'_> for KeyComboVisitor {
     {
         let mut modifiers = ModifiersState::default();
         let mut key: Option<Keysym> = None;
-        value.split('+').for_each(|s| match s {
-            "ctrl" => modifiers.ctrl = true,
-            "shift" => modifiers.shift = true,
-            "alt" => modifiers.alt = true,
-            "logo" => modifiers.logo = true,
-            s => {
-                if let Some(value) = lookup_by_name(s) {
-                    key = Some(Keysym::from(value.keysym));
+        for token in value.split('+') {
+            match token.to_lowercase().as_str() {
+                "ctrl" => modifiers.ctrl = true,
+                "shift" => modifiers.shift = true,
+                "alt" => modifiers.alt = true,
+                "logo" => modifiers.logo = true,
+                _ => {
+                    let Some(record) = lookup_by_name(token) else {
+                        return Err(de::Error::custom(format!(
+                            "unknown key or modifier `{token}` in `{value}`"
+                        )));
+                    };
+                    if key.is_some() {
+                        return Err(de::Error::custom(format!(
+                            "more than one key in `{value}`, combine keys with modifiers (ctrl, shift, alt, logo)"
+                        )));
+                    }
+                    key = Some(Keysym::from(record.keysym));
                 }
             }
-        });
+        }
         key.map_or_else(
-            || Err(de::Error::custom("No key given or unable to parse")),
-            |key| {
-                Ok(KeyCombo {
-                    modifiers: Modifiers(modifiers),
-                    key,
-                })
-            },
+            || Err(de::Error::custom(format!("no key given in `{value}`"))),
+            |key| Ok(KeyCombo::new(Modifiers(modifiers), key)),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_test() {
+        let config = KeybindingsConfig {
+            modal: true,
+            ..KeybindingsConfig::default()
+        };
+        let mut keybindings = Keybindings::try_from(config).unwrap();
+        let mut press = |mode, key| keybindings.get(mode, ModifiersState::default(), key);
+
+        assert!(press(Mode::Insert, Keysym::j).is_none());
+        assert!(matches!(
+            press(Mode::Normal, Keysym::j),
+            Some(Action::NavDown)
+        ));
+        assert!(press(Mode::Normal, Keysym::g).is_none());
+        assert!(matches!(
+            press(Mode::Normal, Keysym::g),
+            Some(Action::First)
+        ));
+        // An unknown sequence starts over with the latest key
+        assert!(press(Mode::Normal, Keysym::d).is_none());
+        assert!(matches!(
+            press(Mode::Normal, Keysym::k),
+            Some(Action::NavUp)
+        ));
+        assert!(press(Mode::Normal, Keysym::d).is_none());
+        assert!(matches!(
+            press(Mode::Normal, Keysym::d),
+            Some(Action::RemoveHistory)
+        ));
+    }
+
+    fn parse(value: &str) -> Result<KeyCombo, de::value::Error> {
+        KeyComboVisitor.visit_str(value)
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(
+            parse("ctlr+v").unwrap_err().to_string(),
+            "unknown key or modifier `ctlr` in `ctlr+v`"
+        );
+        assert!(parse("a+b").is_err());
+        assert!(parse("ctrl+").is_err());
+        assert_eq!(parse("Ctrl+v").unwrap().to_string(), "ctrl+v");
+
+        // Shifted keysyms match the way they are reported by the compositor
+        assert_eq!(parse("ctrl+V").unwrap(), parse("ctrl+shift+v").unwrap());
+        assert_eq!(parse("shift+G").unwrap(), parse("G").unwrap());
+        assert_eq!(parse("ISO_Left_Tab").unwrap(), parse("shift+Tab").unwrap());
+        let shift = ModifiersState {
+            shift: true,
+            ..ModifiersState::default()
+        };
+        assert_eq!(
+            KeyCombo::new(shift.into(), Keysym::ISO_Left_Tab),
+            parse("shift+Tab").unwrap()
+        );
+        assert_eq!(
+            KeyCombo::new(shift.into(), Keysym::question),
+            parse("question").unwrap()
+        );
+        assert_eq!(parse("shift+colon").unwrap(), parse("colon").unwrap());
+        assert_ne!(parse("shift+space").unwrap(), parse("space").unwrap());
+    }
+
+    #[test]
+    fn conflict_test() {
+        let mut config = KeybindingsConfig::default();
+        config.nav_down.push(parse("Tab").unwrap());
+        let err = Keybindings::try_from(config).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "`Tab` is bound to both `complete` and `nav_down`"
+        );
+        assert_eq!(err.key(), "keybindings.nav_down");
+
+        let mut config = KeybindingsConfig {
+            modal: true,
+            ..KeybindingsConfig::default()
+        };
+        config.normal.last.push(parse("g").unwrap().into());
+        let err = Keybindings::try_from(config).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "`g` of `normal.last` hides `g g` of `normal.first`"
+        );
+        assert_eq!(err.key(), "keybindings.normal.last");
+
+        // Normal mode bindings only conflict in modal configs
+        let mut config = KeybindingsConfig::default();
+        for key in ["ctrl+f", "g"] {
+            config.custom.push(CustomKeybinding {
+                key: parse(key).unwrap(),
+                command: key.to_string(),
+            });
+        }
+        assert!(Keybindings::try_from(config.clone()).is_ok());
+        config.modal = true;
+        let err = Keybindings::try_from(config).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "`ctrl+f` is bound to both `normal.page_down` and `custom `ctrl+f``"
+        );
+    }
+}
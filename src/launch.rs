@@ -0,0 +1,581 @@
+use crate::command;
+use crate::config::LaunchConfig;
+use log::debug;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc;
+use nix::sys::signal::{
+    sigaction, sigprocmask, SaFlags, SigAction, SigHandler, SigSet, SigmaskHow, Signal,
+};
+use nix::sys::wait::waitpid;
+use nix::unistd::{fork, pipe2, setsid, ForkResult};
+use std::env;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+
+/// Message on the status pipe: exec failed, followed by the errno
+const STATUS_EXEC_FAILED: u8 = b'E';
+/// Message on the status pipe: the program exited early, followed by the wait status
+const STATUS_EXITED: u8 = b'W';
+const STATUS_LENGTH: usize = 5;
+/// Bytes of the program's stderr passed on to kickoff
+const CAPTURE_LIMIT: usize = 4096;
+/// Where the wayland (xdg-activation) and X11 (startup-notification) toolkits look for the token
+const ACTIVATION_VARIABLES: [&str; 2] = ["XDG_ACTIVATION_TOKEN", "DESKTOP_STARTUP_ID"];
+
+#[derive(Debug)]
+pub enum LaunchError {
+    /// The program could not be started
+    Io(io::Error),
+    /// The program exited unsuccessfully right after the start
+    Exited { status: i32, stderr: String },
+}
+
+impl fmt::Display for LaunchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Exited { status, stderr } => {
+                // The last line usually holds the actual error
+                if let Some(line) = stderr.lines().rev().find(|line| !line.trim().is_empty()) {
+                    write!(f, "{}", line.trim())
+                } else if libc::WIFSIGNALED(*status) {
+                    write!(f, "killed by signal {}", libc::WTERMSIG(*status))
+                } else {
+                    write!(f, "exited with status {}", libc::WEXITSTATUS(*status))
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for LaunchError {}
+
+impl From<io::Error> for LaunchError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<Errno> for LaunchError {
+    fn from(errno: Errno) -> Self {
+        Self::Io(errno.into())
+    }
+}
+
+/// Everything the launched program needs, prepared before forking.
+/// Only async-signal-safe functions may be called in the child of a multithreaded process,
+/// so nothing is allocated after the fork.
+struct Prepared {
+    argv: Vec<CString>,
+    envp: Vec<CString>,
+    working_directory: Option<CString>,
+    stdin: File,
+    max_fd: RawFd,
+    /// Milliseconds to watch the program for an early exit
+    early_exit_timeout: i64,
+}
+
+/// Pipe ends used by the forked processes
+struct Pipes {
+    /// Closed on exec, receives the status messages
+    status: RawFd,
+    /// Read and write end of the program's stderr socket, if it is watched
+    stderr: Option<(RawFd, RawFd)>,
+    /// The beginning of stderr is copied here for kickoff
+    capture: Option<RawFd>,
+}
+
+/// What is launched
+#[derive(Clone, Copy, Debug)]
+pub enum Program<'a> {
+    /// Command run by the configured shell
+    Shell(&'a str),
+    /// Executable looked up in `$PATH`, started without a shell
+    Executable(&'a str),
+    /// Executable looked up in `$PATH` with its arguments, started without a shell
+    Exec(&'a [String]),
+}
+
+impl Program<'_> {
+    /// Arguments to execute the program, wrapped into `config.wrapper` if one is set.
+    /// The wrapper itself is run by the shell.
+    fn argv(self, name: &str, config: &LaunchConfig) -> Vec<String> {
+        let shell = |command: String| vec![config.shell.clone(), "-c".to_string(), command];
+        match (config.wrapper.as_ref(), self) {
+            (Some(wrapper), program) => {
+                let command = match program {
+                    Self::Shell(command) => {
+                        format!(
+                            "{} -c {}",
+                            command::quote(&config.shell),
+                            command::quote(command)
+                        )
+                    }
+                    Self::Executable(executable) => command::quote(executable),
+                    Self::Exec(argv) => command::join(argv),
+                };
+                shell(command::wrap(
+                    wrapper,
+                    &command,
+                    name,
+                    &command::random_id(),
+                ))
+            }
+            (None, Self::Shell(command)) => shell(command.to_string()),
+            (None, Self::Executable(executable)) => vec![executable.to_string()],
+            (None, Self::Exec(argv)) => argv.to_vec(),
+        }
+    }
+}
+
+/// A started program whose result is not known yet, see `Launched::poll`
+pub struct Launched {
+    /// Non-blocking read end of the status pipe, closed once the program was executed
+    /// and, with `early_exit_timeout` set, the watcher is done
+    status: File,
+    /// Status messages received so far
+    messages: Vec<u8>,
+    /// Read end of the pipe with the beginning of the program's stderr, if it is watched
+    capture: Option<File>,
+}
+
+impl Launched {
+    /// Becomes readable whenever `poll` may make progress
+    pub fn fd(&self) -> io::Result<OwnedFd> {
+        Ok(self.status.try_clone()?.into())
+    }
+
+    /// Reads the status messages without blocking,
+    /// returns the result once the program was executed and watched
+    pub fn poll(&mut self) -> Option<Result<(), LaunchError>> {
+        let mut buffer = [0; 64];
+        loop {
+            match self.status.read(&mut buffer) {
+                Ok(0) => return Some(self.finish()),
+                Ok(length) => self.messages.extend_from_slice(&buffer[..length]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+
+    /// Blocks until the result is known
+    pub fn wait(mut self) -> Result<(), LaunchError> {
+        loop {
+            if let Some(result) = self.poll() {
+                return result;
+            }
+            let mut poll = libc::pollfd {
+                fd: self.status.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            unsafe { libc::poll(&mut poll, 1, -1) };
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), LaunchError> {
+        // The watcher is gone, so reading the capture pipe ends as well
+        let mut stderr = Vec::new();
+        if let Some(mut reader) = self.capture.take() {
+            reader.read_to_end(&mut stderr)?;
+        }
+
+        let mut exit_status = None;
+        for message in self.messages.chunks_exact(STATUS_LENGTH) {
+            let value = i32::from_ne_bytes(message[1..].try_into().unwrap());
+            match message[0] {
+                STATUS_EXEC_FAILED => {
+                    return Err(io::Error::from_raw_os_error(value).into());
+                }
+                STATUS_EXITED => exit_status = Some(value),
+                _ => {}
+            }
+        }
+        match exit_status {
+            Some(status) if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 => {
+                Err(LaunchError::Exited {
+                    status,
+                    stderr: String::from_utf8_lossy(&stderr).into_owned(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Starts the program detached from kickoff in a new session.
+/// Returns right away, the returned `Launched` tells once the program was executed,
+/// or why it could not be. With `early_exit_timeout` set, the program is also watched
+/// for that long and fails if it exits unsuccessfully in the meantime.
+/// An `activation_token` lets the program take the focus from kickoff, it replaces the one
+/// kickoff was started with, which is never passed on.
+pub fn launch(
+    program: Program,
+    name: &str,
+    config: &LaunchConfig,
+    activation_token: Option<&str>,
+) -> Result<Launched, LaunchError> {
+    let argv = program.argv(name, config);
+    debug!("Launching: {argv:?}");
+
+    let prepared = prepare(argv, config, activation_token)?;
+    let argv = pointers(&prepared.argv);
+    let envp = pointers(&prepared.envp);
+    let (status_reader, status_writer) = pipe()?;
+    let watch = if prepared.early_exit_timeout > 0 {
+        Some((datagram_pair()?, pipe()?))
+    } else {
+        None
+    };
+    let pipes = Pipes {
+        status: status_writer.as_raw_fd(),
+        stderr: watch
+            .as_ref()
+            .map(|((reader, writer), _)| (reader.as_raw_fd(), writer.as_raw_fd())),
+        capture: watch.as_ref().map(|(_, (_, writer))| writer.as_raw_fd()),
+    };
+
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            // Fork again in a new session, the program is adopted by init and
+            // neither becomes a zombie of kickoff nor dies with its terminal
+            let result = setsid().and_then(|_| unsafe { fork() });
+            match result {
+                Ok(ForkResult::Child) => watch_program(&prepared, &argv, &envp, &pipes),
+                Ok(ForkResult::Parent { .. }) => unsafe { libc::_exit(0) },
+                Err(errno) => report(pipes.status, errno),
+            }
+        }
+        ForkResult::Parent { child } => {
+            // Only the children may keep the write ends, otherwise reading never ends
+            drop(status_writer);
+            let capture = watch.map(|(_, (reader, _))| reader);
+            // Returns right away, the first child only forks
+            waitpid(child, None)?;
+            fcntl(&status_reader, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+            Ok(Launched {
+                status: status_reader,
+                messages: Vec::new(),
+                capture,
+            })
+        }
+    }
+}
+
+fn pipe() -> io::Result<(File, OwnedFd)> {
+    let (reader, writer) = pipe2(OFlag::O_CLOEXEC)?;
+    Ok((File::from(reader), writer))
+}
+
+/// Connected datagram sockets, used for the program's stderr instead of a pipe since
+/// writing to them fails without a SIGPIPE once kickoff stops reading
+fn datagram_pair() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    let kind = libc::SOCK_DGRAM | libc::SOCK_CLOEXEC;
+    if unsafe { libc::socketpair(libc::AF_UNIX, kind, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+fn prepare(
+    argv: Vec<String>,
+    config: &LaunchConfig,
+    activation_token: Option<&str>,
+) -> io::Result<Prepared> {
+    let argv = argv
+        .into_iter()
+        .map(CString::new)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut envp = Vec::new();
+    for (key, value) in env::vars_os() {
+        if key
+            .to_str()
+            .is_some_and(|key| config.env.contains_key(key) || ACTIVATION_VARIABLES.contains(&key))
+        {
+            continue;
+        }
+        let mut variable = key.as_bytes().to_vec();
+        variable.push(b'=');
+        variable.extend_from_slice(value.as_bytes());
+        envp.push(CString::new(variable)?);
+    }
+    for (key, value) in &config.env {
+        envp.push(CString::new(format!("{key}={value}"))?);
+    }
+    if let Some(token) = activation_token {
+        for key in ACTIVATION_VARIABLES {
+            envp.push(CString::new(format!("{key}={token}"))?);
+        }
+    }
+
+    let working_directory = config
+        .working_directory
+        .clone()
+        .or_else(|| env::var_os("HOME").map(PathBuf::from))
+        .map(|dir| CString::new(dir.as_os_str().as_bytes()))
+        .transpose()?;
+
+    let max_fd = match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+        max if max > 0 => max.min(65536) as RawFd,
+        _ => 1024,
+    };
+
+    Ok(Prepared {
+        argv,
+        envp,
+        working_directory,
+        stdin: File::open("/dev/null")?,
+        max_fd,
+        early_exit_timeout: config.early_exit_timeout as i64,
+    })
+}
+
+/// Null terminated array of pointers for exec
+fn pointers(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings
+        .iter()
+        .map(|s| s.as_ptr())
+        .chain([ptr::null()])
+        .collect()
+}
+
+/// Runs in the detached child, resets what was inherited from kickoff and executes the program
+fn exec(
+    prepared: &Prepared,
+    argv: &[*const libc::c_char],
+    envp: &[*const libc::c_char],
+    pipes: &Pipes,
+) -> ! {
+    // Ignored signals and the signal mask survive exec, Rust for example ignores SIGPIPE
+    let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+    for signal in Signal::iterator() {
+        if signal != Signal::SIGKILL && signal != Signal::SIGSTOP {
+            let _ = unsafe { sigaction(signal, &default) };
+        }
+    }
+    let _ = sigprocmask(SigmaskHow::SIG_SETMASK, Some(&SigSet::empty()), None);
+
+    unsafe {
+        libc::dup2(prepared.stdin.as_raw_fd(), libc::STDIN_FILENO);
+        if let Some((_, writer)) = pipes.stderr {
+            libc::dup2(writer, libc::STDERR_FILENO);
+        }
+
+        // Don't pass on kickoff's files, like the wayland socket, only the status pipe stays open until exec
+        let first: libc::c_uint = 3;
+        let closed = libc::syscall(
+            libc::SYS_close_range,
+            first,
+            libc::c_uint::MAX,
+            libc::CLOSE_RANGE_CLOEXEC,
+        );
+        if closed != 0 {
+            for fd in 3..prepared.max_fd {
+                if fd != pipes.status {
+                    libc::close(fd);
+                }
+            }
+        }
+
+        if let Some(dir) = &prepared.working_directory {
+            libc::chdir(dir.as_ptr());
+        }
+
+        libc::execvpe(argv[0], argv.as_ptr(), envp.as_ptr());
+    }
+    report(pipes.status, Errno::last())
+}
+
+/// Starts the program and watches it for `early_exit_timeout` milliseconds while capturing
+/// the beginning of its stderr for kickoff. Afterwards the watcher exits, so the program is
+/// adopted by init, and whatever it writes to stderr is dropped.
+fn watch_program(
+    prepared: &Prepared,
+    argv: &[*const libc::c_char],
+    envp: &[*const libc::c_char],
+    pipes: &Pipes,
+) -> ! {
+    let (Some((stderr, stderr_writer)), Some(capture)) = (pipes.stderr, pipes.capture) else {
+        exec(prepared, argv, envp, pipes)
+    };
+    let pid = match unsafe { fork() } {
+        Ok(ForkResult::Child) => exec(prepared, argv, envp, pipes),
+        Ok(ForkResult::Parent { child }) => child.as_raw(),
+        Err(errno) => report(pipes.status, errno),
+    };
+
+    unsafe {
+        libc::close(stderr_writer);
+        for fd in 3..prepared.max_fd {
+            if ![stderr, capture, pipes.status].contains(&fd) {
+                libc::close(fd);
+            }
+        }
+    }
+
+    let mut capture = Capture {
+        stderr,
+        capture,
+        captured: 0,
+    };
+    let deadline = now() + prepared.early_exit_timeout;
+    let mut wait_status = 0;
+    while now() < deadline {
+        capture.poll((deadline - now()).min(10));
+        if unsafe { libc::waitpid(pid, &mut wait_status, libc::WNOHANG) } == pid {
+            // Catch what was written right before the exit
+            while capture.poll(0) {}
+            write_status(pipes.status, STATUS_EXITED, wait_status);
+            break;
+        }
+    }
+    // Closing the socket makes later writes of the program fail without a SIGPIPE
+    unsafe { libc::_exit(0) }
+}
+
+/// Copies the first `CAPTURE_LIMIT` bytes of the program's stderr to the capture pipe
+struct Capture {
+    stderr: RawFd,
+    capture: RawFd,
+    captured: usize,
+}
+
+impl Capture {
+    /// Waits up to `timeout` milliseconds for output and captures it.
+    /// Returns whether something was read.
+    fn poll(&mut self, timeout: i64) -> bool {
+        let mut poll = libc::pollfd {
+            fd: self.stderr,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut poll, 1, timeout as libc::c_int) } <= 0 {
+            return false;
+        }
+
+        let mut buffer = [0; 1024];
+        let length = unsafe { libc::read(self.stderr, buffer.as_mut_ptr().cast(), buffer.len()) };
+        if length < 0 {
+            return false;
+        }
+        let copied = (length as usize).min(CAPTURE_LIMIT - self.captured);
+        unsafe { libc::write(self.capture, buffer.as_ptr().cast(), copied) };
+        self.captured += copied;
+        true
+    }
+}
+
+/// Monotonic time in milliseconds
+fn now() -> i64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec * 1000 + time.tv_nsec / 1_000_000
+}
+
+fn write_status(status: RawFd, kind: u8, value: i32) {
+    let value = value.to_ne_bytes();
+    let message = [kind, value[0], value[1], value[2], value[3]];
+    unsafe { libc::write(status, message.as_ptr().cast(), message.len()) };
+}
+
+/// Tells kickoff why the program could not be executed and exits the child
+fn report(status: RawFd, errno: Errno) -> ! {
+    write_status(status, STATUS_EXEC_FAILED, errno as i32);
+    unsafe { libc::_exit(127) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(
+        program: Program,
+        config: &LaunchConfig,
+        token: Option<&str>,
+    ) -> Result<(), LaunchError> {
+        launch(program, "test", config, token)?.wait()
+    }
+
+    #[test]
+    fn launch_test() {
+        let mut config = LaunchConfig {
+            working_directory: Some(PathBuf::from("/")),
+            ..LaunchConfig::default()
+        };
+        config.early_exit_timeout = 0;
+        assert!(run(Program::Shell("exit 0"), &config, None).is_ok());
+        // The exit status of the program itself is only checked while it is watched
+        assert!(run(Program::Shell("exit 1"), &config, None).is_ok());
+
+        // The watcher reports as soon as the program exits, the timeout is never reached
+        config.early_exit_timeout = 60_000;
+        assert!(run(Program::Shell("exit 0"), &config, None).is_ok());
+        let program = Program::Shell("echo first >&2; echo 'not found' >&2; exit 3");
+        match run(program, &config, None) {
+            Err(LaunchError::Exited { status, stderr }) => {
+                assert_eq!(libc::WEXITSTATUS(status), 3);
+                assert_eq!(stderr, "first\nnot found\n");
+            }
+            result => panic!("expected an early exit, got {result:?}"),
+        }
+        let err = run(Program::Shell("exit 4"), &config, None).unwrap_err();
+        assert_eq!(err.to_string(), "exited with status 4");
+
+        let program = Program::Shell(r#"[ "$XDG_ACTIVATION_TOKEN$DESKTOP_STARTUP_ID" = tt ]"#);
+        assert!(run(program, &config, Some("t")).is_ok());
+        assert!(run(program, &config, None).is_err());
+    }
+
+    #[test]
+    fn executable_test() {
+        let config = LaunchConfig::default();
+        assert!(run(Program::Executable("true"), &config, None).is_ok());
+        // Not passed to a shell, so this is a single file name
+        let err = run(Program::Executable("true; false"), &config, None).unwrap_err();
+        assert!(matches!(err, LaunchError::Io(e) if e.kind() == io::ErrorKind::NotFound));
+        let argv = ["sh".to_string(), "-c".to_string(), "exit 0".to_string()];
+        assert!(run(Program::Exec(&argv), &config, None).is_ok());
+        let argv = ["true; false".to_string()];
+        assert!(run(Program::Exec(&argv), &config, None).is_err());
+
+        assert_eq!(Program::Executable("foot").argv("Foot", &config), ["foot"]);
+        assert_eq!(
+            Program::Shell("foot -e htop").argv("Foot", &config),
+            ["sh", "-c", "foot -e htop"]
+        );
+        let argv = ["foot".to_string(), "-e".to_string(), "htop".to_string()];
+        assert_eq!(Program::Exec(&argv).argv("Foot", &config), argv);
+        let config = LaunchConfig {
+            shell: "bash".to_string(),
+            wrapper: Some("uwsm app -- {cmd}".to_string()),
+            ..LaunchConfig::default()
+        };
+        assert_eq!(
+            Program::Executable("foot").argv("Foot", &config),
+            ["bash", "-c", "uwsm app -- 'foot'"]
+        );
+        assert_eq!(
+            Program::Shell("foot -e htop").argv("Foot", &config),
+            ["bash", "-c", "uwsm app -- 'bash' -c 'foot -e htop'"]
+        );
+        assert_eq!(
+            Program::Exec(&argv).argv("Foot", &config),
+            ["bash", "-c", "uwsm app -- 'foot' '-e' 'htop'"]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use crate::shapes::Rect;
+use std::ops::Range;
+
+/// Positions of the query and the result rows in buffer pixels,
+/// shared between drawing and hit-testing of pointer events
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub width: u32,
+    pub padding: u32,
+    pub font_size: f32,
+    /// Height of a line of text as rendered by the font
+    pub text_height: f32,
+    /// Distance between two lines
+    pub row_height: f32,
+    /// Lines used by a single result
+    pub entry_lines: f32,
+    /// Space between the query and the first result
+    pub spacer: u32,
+    pub max_entries: usize,
+    /// Index of the first visible result
+    pub offset: usize,
+    pub result_count: usize,
+}
+
+impl Layout {
+    pub fn entry_height(&self) -> f32 {
+        self.row_height * self.entry_lines
+    }
+
+    /// Indices of the results that fit into the window
+    pub fn visible_entries(&self) -> Range<usize> {
+        self.offset..self.result_count.min(self.offset + self.max_entries)
+    }
+
+    /// Top of the text of the result at `index`
+    pub fn entry_y(&self, index: usize) -> u32 {
+        self.padding
+            + self.spacer
+            + index.saturating_sub(self.offset) as u32 * self.entry_height() as u32
+    }
+
+    /// Area behind `lines` lines of text starting at `y`, centered around the text
+    /// and reaching slightly into the padding
+    pub fn highlight_rect(&self, y: u32, lines: f32) -> Rect {
+        let inset = (self.font_size * 0.25).min(self.padding as f32);
+        Rect::new(
+            self.padding as f32 - inset,
+            (self.text_height - self.row_height).mul_add(0.5, y as f32),
+            2f32.mul_add(inset, self.width.saturating_sub(2 * self.padding) as f32),
+            self.row_height * lines,
+        )
+    }
+
+    pub fn query_rect(&self) -> Rect {
+        self.highlight_rect(self.padding, 1.)
+    }
+
+    pub fn entry_rect(&self, index: usize) -> Rect {
+        self.highlight_rect(self.entry_y(index), self.entry_lines)
+    }
+
+    /// Index of the result at the given position
+    pub fn entry_at(&self, x: f32, y: f32) -> Option<usize> {
+        self.visible_entries()
+            .find(|index| self.entry_rect(*index).contains(x, y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_at_test() {
+        let layout = Layout {
+            width: 400,
+            padding: 10,
+            font_size: 20.,
+            text_height: 20.,
+            row_height: 30.,
+            entry_lines: 1.,
+            spacer: 40,
+            max_entries: 5,
+            offset: 2,
+            result_count: 6,
+        };
+
+        assert_eq!(layout.visible_entries(), 2..6);
+        assert_eq!(layout.entry_y(3), 80);
+        // Rows start half the spacing above the text
+        assert_eq!(layout.entry_at(100., 45.), Some(2));
+        assert_eq!(layout.entry_at(100., 74.), Some(2));
+        assert_eq!(layout.entry_at(100., 76.), Some(3));
+        assert_eq!(layout.entry_at(100., 20.), None);
+        assert_eq!(layout.entry_at(100., 300.), None);
+    }
+}
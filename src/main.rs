@@ -4,26 +4,34 @@
 use anyhow::Result;
 use app::App;
 use clap::Parser;
-use config::{Config, History};
+use config::{Config, Diagnostic, History, LoadedConfig, Overrides, Severity};
+use keybinds::Keybindings;
 use log::{debug, error, warn};
 use std::time::Instant;
 use std::{
+    fs,
     io::{Read, Write},
-    {fs, io::ErrorKind},
     {path::PathBuf, process},
 };
 use xdg::BaseDirectories;
 
 mod app;
 mod color;
+mod command;
 mod config;
 mod font;
 mod gui;
 mod keybinds;
+mod launch;
+mod layout;
 mod selection;
+mod shapes;
+mod truncate;
+mod watch;
 
 #[derive(Parser, Debug)]
 pub struct Args {
+    /// Config file to use instead of the layered config from the config directories
     #[clap(short, long)]
     config: Option<PathBuf>,
 
@@ -42,23 +50,50 @@ pub struct Args {
     #[clap(long)]
     from_file: Vec<PathBuf>,
 
+    /// Format of the list read from stdin or files, files ending in .jsonl default to jsonl
+    #[clap(long, value_enum)]
+    input_format: Option<selection::InputFormat>,
+
     /// Output selection to stdout instead of executing it
     #[clap(long)]
     stdout: bool,
 
+    /// Name of the theme to use, overwrites config if set
+    #[clap(long)]
+    theme: Option<String>,
+
+    /// Set a config value, i.e. `--set colors.background=#ff000080`, overwrites config
+    /// and KICKOFF_* environment variables like KICKOFF_COLORS__BACKGROUND
+    #[clap(long, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+
+    /// Name or description of the output to show kickoff on, overwrites config if set
+    #[clap(long)]
+    output: Option<String>,
+
     /// Set custom history name. Default history will only be used if stdin is not set
     #[clap(long)]
     history: Option<PathBuf>,
+
+    /// Check the config file, or the one given by --config, and exit
+    #[clap(long, value_name = "PATH")]
+    check_config: Option<Option<PathBuf>>,
+
+    /// Print the config merged from all config files, including defaults, and exit
+    #[clap(long)]
+    print_config: bool,
 }
 
 #[cfg(target_os = "linux")]
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
+    let args = Args::parse();
+    run_config_command(&args);
 
     match put_pid() {
         Ok(()) => {
-            run().await?;
+            run(args).await?;
             del_pid()?;
             Ok(())
         }
@@ -72,7 +107,9 @@ async fn main() -> Result<()> {
 #[cfg(not(target_os = "linux"))]
 #[tokio::main]
 async fn main() -> Result<()> {
-    run().await
+    let args = Args::parse();
+    run_config_command(&args);
+    run(args).await
 }
 
 #[cfg(target_os = "linux")]
@@ -91,10 +128,7 @@ fn put_pid() -> std::io::Result<()> {
             file_handle.read_to_string(&mut pid)?;
             if !pid.is_empty() && fs::metadata(format!("/proc/{pid}")).is_ok() {
                 debug!("Pid from pid file still alive");
-                Err(std::io::Error::new(
-                    ErrorKind::Other,
-                    "Kickoff is already running",
-                ))
+                Err(std::io::Error::other("Kickoff is already running"))
             } else {
                 debug!("Pid from kickoff.pid not alive, overwriting...");
                 let mut pid_file = fs::File::create(pid_path)?;
@@ -113,13 +147,103 @@ fn del_pid() -> std::io::Result<()> {
     Ok(())
 }
 
-async fn run() -> Result<()> {
+/// Config values set by the command line and environment, exits if one is invalid
+fn overrides(args: &Args) -> Overrides {
+    match Overrides::new(args.theme.as_deref(), &args.set) {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs `--check-config` or `--print-config` and exits, if one of them is given
+fn run_config_command(args: &Args) {
+    if let Some(path) = &args.check_config {
+        process::exit(check_config(
+            path.clone().or_else(|| args.config.clone()),
+            &overrides(args),
+        ));
+    }
+    if args.print_config {
+        match Config::load_files(&Config::files(args.config.clone()), &overrides(args)) {
+            Ok(loaded) => match toml::to_string(&loaded.config) {
+                Ok(config) => print!("{config}"),
+                Err(e) => {
+                    eprintln!("{e}");
+                    process::exit(1);
+                }
+            },
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+        }
+        process::exit(0);
+    }
+}
+
+/// Prints all problems of the config files and returns the exit code,
+/// which is 1 if there are errors
+fn check_config(path: Option<PathBuf>, overrides: &Overrides) -> i32 {
+    let files = Config::files(path);
+    if files.is_empty() {
+        println!("No config file found, the default config is used");
+        return 0;
+    }
+
+    let diagnostics = match Config::load_files(&files, overrides) {
+        Ok(loaded) => {
+            let mut diagnostics = loaded.warnings;
+            if let Err(e) = Keybindings::try_from(loaded.config.keybindings.clone()) {
+                diagnostics.push(Diagnostic::at_key(&loaded.files, e.key(), e.to_string()));
+            }
+            diagnostics.extend(font::check(&loaded.config.font_names()));
+            diagnostics
+        }
+        Err(e) => vec![e],
+    };
+    for diagnostic in &diagnostics {
+        eprintln!("{diagnostic}");
+    }
+
+    if diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+    {
+        1
+    } else {
+        for file in files {
+            println!("{}: ok", file.display());
+        }
+        0
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
     let start = Instant::now();
-    let args = Args::parse();
-    let config = match Config::load(args.config.clone()) {
-        Ok(c) => c,
+    let LoadedConfig {
+        config,
+        files: config_files,
+        warnings,
+    } = match Config::load(args.config.clone(), &overrides(&args)) {
+        Ok(loaded) => loaded,
         Err(e) => {
-            error!("{e}");
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
+    for warning in &warnings {
+        eprintln!("{warning}");
+    }
+    let keybindings = match Keybindings::try_from(config.keybindings.clone()) {
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!(
+                "{}",
+                Diagnostic::at_key(&config_files, e.key(), e.to_string())
+            );
             process::exit(1);
         }
     };
@@ -134,13 +258,7 @@ async fn run() -> Result<()> {
         None
     };
 
-    let font = if let Some(font_name) = config.font.clone() {
-        let mut font_names = config.fonts.clone();
-        font_names.insert(0, font_name);
-        font::Font::new(font_names, config.font_size)
-    } else {
-        font::Font::new(config.fonts.clone(), config.font_size)
-    };
+    let font = font::Font::new(config.font_names(), config.font_size);
 
     let mut apps = selection::ElementListBuilder::new();
     if args.from_path || (!args.from_stdin && args.from_file.is_empty()) {
@@ -152,6 +270,9 @@ async fn run() -> Result<()> {
     if args.from_stdin {
         apps.add_stdin();
     }
+    if let Some(format) = args.input_format {
+        apps.set_input_format(format);
+    }
     let apps = apps.build();
     let mut apps = apps.await?;
 
@@ -164,10 +285,15 @@ async fn run() -> Result<()> {
         None => None,
     };
     apps.sort_score();
+    apps.set_aliases(&config.aliases);
 
     let elapsed = start.elapsed();
     debug!("Time till gui: {elapsed:?}");
-    gui::run(App::new(args, config, apps, font.await?, history));
+    gui::run(
+        App::new(args, config, apps, font.await?, history),
+        keybindings,
+        config_files,
+    );
 
     Ok(())
 }
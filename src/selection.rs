@@ -1,11 +1,15 @@
+use crate::command;
 use crate::config::{self, History};
+use clap::ValueEnum;
 use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
 use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs::File;
 use std::{
     cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd},
     io::{BufRead, BufReader},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 use std::{env, os::unix::fs::PermissionsExt};
 use tokio::{
@@ -17,7 +21,26 @@ use tokio::{
 pub struct Element {
     pub name: String,
     pub value: String,
+    pub description: Option<String>,
+    /// Additional names the element can be found by
+    pub keywords: Vec<String>,
+    /// Icon name or path, from structured input
+    pub icon: Option<String>,
+    /// Arbitrary data attached by structured input
+    pub meta: Option<serde_json::Value>,
     pub base_score: usize,
+    pub source: Source,
+}
+
+/// Where an element comes from, decides how its value is launched
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Source {
+    /// Executable in `$PATH`, started without a shell
+    Path,
+    /// Line of a file or stdin, history entry or typed input, the value is run by the shell
+    Shell,
+    /// JSON line with an `exec` array, the arguments are executed without a shell
+    Exec(Vec<String>),
 }
 
 impl Ord for Element {
@@ -38,9 +61,25 @@ impl PartialOrd for Element {
 #[derive(Debug, Default)]
 pub struct ElementList {
     inner: Vec<Element>,
+    /// Keywords from the config, by lowercase name of the element
+    aliases: HashMap<String, Vec<String>>,
 }
 
 impl ElementList {
+    /// Replaces the keywords from the config, given as a map from keyword to the name of
+    /// the element. Names are matched ignoring case, like the search does.
+    pub fn set_aliases(&mut self, aliases: &HashMap<String, String>) {
+        self.aliases.clear();
+        for (keyword, name) in aliases {
+            let name = name.to_lowercase();
+            if !self.inner.iter().any(|x| x.name.to_lowercase() == name) {
+                warn!("Alias `{keyword}` refers to the unknown entry `{name}`");
+                continue;
+            }
+            self.aliases.entry(name).or_default().push(keyword.clone());
+        }
+    }
+
     pub fn merge_history(&mut self, history: &History) {
         for entry in history.as_vec() {
             if let Some(elem) = self.inner.iter_mut().find(|x| x.name == entry.name) {
@@ -49,46 +88,235 @@ impl ElementList {
                 self.inner.push(Element {
                     name: entry.name.clone(),
                     value: entry.value.clone(),
+                    description: None,
+                    keywords: Vec::new(),
+                    icon: None,
+                    meta: None,
                     base_score: entry.num_used,
+                    source: Source::Shell,
                 });
             }
         }
     }
 
+    /// Drops the score an element got from the history
+    pub fn reset_score(&mut self, name: &str) {
+        for elem in self.inner.iter_mut().filter(|x| x.name == name) {
+            elem.base_score = 0;
+        }
+    }
+
     pub fn sort_score(&mut self) {
-        self.inner.sort_by(|a, b| b.base_score.cmp(&a.base_score));
+        self.inner.sort_by_key(|x| std::cmp::Reverse(x.base_score));
     }
 
-    pub fn search(&self, pattern: &str) -> Vec<&Element> {
+    pub fn search(&self, pattern: &str, match_description: bool) -> Vec<&Element> {
         let matcher = SkimMatcherV2::default();
         let mut executables = self
             .inner
             .iter()
             .map(|x| {
+                let name_score = matcher.fuzzy_match(&x.name, pattern);
+                // Matches in keywords count three quarters, in the description half as much as in the name
+                let aliases = if self.aliases.is_empty() {
+                    None
+                } else {
+                    self.aliases.get(&x.name.to_lowercase())
+                };
+                let keyword_score = x
+                    .keywords
+                    .iter()
+                    .chain(aliases.into_iter().flatten())
+                    .filter_map(|keyword| matcher.fuzzy_match(keyword, pattern))
+                    .max()
+                    .map(|score| score * 3 / 4);
+                let description_score = x
+                    .description
+                    .as_ref()
+                    .filter(|_| match_description)
+                    .and_then(|description| matcher.fuzzy_match(description, pattern))
+                    .map(|score| score / 2);
                 (
-                    matcher
-                        .fuzzy_match(&x.name, pattern)
+                    name_score
+                        .max(keyword_score)
+                        .max(description_score)
                         .map(|score| score + x.base_score as i64),
                     x,
                 )
             })
             .filter(|x| x.0.is_some())
             .collect::<Vec<(Option<i64>, &Element)>>();
-        executables.sort_by(|a, b| b.0.unwrap_or(0).cmp(&a.0.unwrap_or(0)));
+        executables.sort_by_key(|x| std::cmp::Reverse(x.0.unwrap_or(0)));
         executables.into_iter().map(|x| x.1).collect()
     }
 
+    /// Indices of the characters in `name` matched by `pattern`
+    pub fn match_indices(name: &str, pattern: &str) -> Vec<usize> {
+        SkimMatcherV2::default()
+            .fuzzy_indices(name, pattern)
+            .map(|(_, indices)| indices)
+            .unwrap_or_default()
+    }
+
     pub fn as_ref_vec(&self) -> Vec<&Element> {
         self.inner.iter().collect()
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum InputFormat {
+    /// One `name=value` pair per line
+    #[default]
+    Text,
+    /// Like `text`, but a tab separates an optional description
+    Tsv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl InputFormat {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") => Self::Jsonl,
+            Some("tsv") => Self::Tsv,
+            _ => Self::Text,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonElement {
+    name: String,
+    value: Option<String>,
+    description: Option<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    icon: Option<String>,
+    meta: Option<serde_json::Value>,
+    score: Option<usize>,
+    exec: Option<Vec<String>>,
+}
+
+/// Turns the lines of a file or stdin into elements and keeps track of magic words
+struct LineParser {
+    format: InputFormat,
+    source: String,
+    line_number: usize,
+    base_score: usize,
+}
+
+impl LineParser {
+    const fn new(format: InputFormat, source: String) -> Self {
+        Self {
+            format,
+            source,
+            line_number: 0,
+            base_score: 0,
+        }
+    }
+
+    /// Skips lines that can't be parsed, they are printed to stderr with their position
+    /// like config diagnostics, since the default log level hides warnings
+    fn parse(&mut self, line: &str) -> Option<Element> {
+        self.try_parse(line).unwrap_or_else(|e| {
+            eprintln!("{e}, skipping the line");
+            None
+        })
+    }
+
+    fn try_parse(&mut self, line: &str) -> Result<Option<Element>, String> {
+        self.line_number += 1;
+        match self.format {
+            InputFormat::Text => Ok(self.parse_text(line, None)),
+            InputFormat::Tsv => Ok(match line.split_once('\t') {
+                Some((line, description)) => {
+                    self.parse_text(line, Some(description.trim()).filter(|d| !d.is_empty()))
+                }
+                None => self.parse_text(line, None),
+            }),
+            InputFormat::Jsonl => self.parse_json(line),
+        }
+    }
+
+    /// Parses `name=value` lines and the `%base_score` magic word
+    fn parse_text(&mut self, line: &str, description: Option<&str>) -> Option<Element> {
+        match parse_line(line)? {
+            ("%base_score", Some(value)) => {
+                if let Ok(value) = value.parse::<usize>() {
+                    self.base_score = value;
+                }
+                None
+            }
+            ("", None) => None, // Empty Line
+            (key, value) => Some(Element {
+                name: key.to_string(),
+                value: value.unwrap_or(key).to_string(),
+                description: description.map(str::to_string),
+                keywords: Vec::new(),
+                icon: None,
+                meta: None,
+                base_score: self.base_score,
+                source: Source::Shell,
+            }),
+        }
+    }
+
+    fn parse_json(&self, line: &str) -> Result<Option<Element>, String> {
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+        let element: JsonElement = serde_json::from_str(line).map_err(|e| {
+            // The position is reported relative to the whole input instead
+            let message = e.to_string();
+            let message = message
+                .rsplit_once(" at line ")
+                .map_or(message.as_str(), |(message, _)| message);
+            format!(
+                "{}:{}:{}: {message}",
+                self.source,
+                self.line_number,
+                e.column()
+            )
+        })?;
+
+        let (value, source) = match element.exec {
+            Some(argv) if argv.is_empty() => {
+                return Err(format!(
+                    "{}:{}: exec is empty",
+                    self.source, self.line_number
+                ))
+            }
+            // The value is printed with --stdout and run by the shell from the history
+            Some(argv) => (
+                element.value.unwrap_or_else(|| command::join(&argv)),
+                Source::Exec(argv),
+            ),
+            None => (
+                element.value.unwrap_or_else(|| element.name.clone()),
+                Source::Shell,
+            ),
+        };
+
+        Ok(Some(Element {
+            value,
+            name: element.name,
+            description: element.description,
+            keywords: element.keywords,
+            icon: element.icon,
+            meta: element.meta,
+            base_score: element.score.unwrap_or(0),
+            source,
+        }))
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ElementListBuilder {
     path_config: config::SearchConfig,
     from_path: bool,
     from_stdin: bool,
     from_file: Vec<PathBuf>,
+    input_format: Option<InputFormat>,
 }
 
 impl ElementListBuilder {
@@ -106,15 +334,22 @@ impl ElementListBuilder {
     pub fn add_stdin(&mut self) {
         self.from_stdin = true;
     }
+    /// Overwrites the input format of stdin and files, which is guessed otherwise
+    pub fn set_input_format(&mut self, format: InputFormat) {
+        self.input_format = Some(format);
+    }
 
     pub async fn build(&self) -> Result<ElementList, std::io::Error> {
         let mut fut = Vec::new();
         if self.from_stdin {
-            fut.push(spawn(Self::build_stdin()));
+            fut.push(spawn(Self::build_stdin(
+                self.input_format.unwrap_or_default(),
+            )));
         }
         if !self.from_file.is_empty() {
             let files = self.from_file.clone();
-            fut.push(spawn_blocking(move || Self::build_files(&files)));
+            let format = self.input_format;
+            fut.push(spawn_blocking(move || Self::build_files(&files, format)));
         }
         if self.from_path {
             let show_hidden = self.path_config.show_hidden_files;
@@ -129,38 +364,28 @@ impl ElementListBuilder {
             res.append(&mut elements);
         }
 
-        Ok(ElementList { inner: res })
+        Ok(ElementList {
+            inner: res,
+            aliases: HashMap::new(),
+        })
     }
 
-    fn build_files(files: &[PathBuf]) -> Result<Vec<Element>, std::io::Error> {
+    fn build_files(
+        files: &[PathBuf],
+        format: Option<InputFormat>,
+    ) -> Result<Vec<Element>, std::io::Error> {
         let mut res = Vec::new();
         for file in files {
             let mut reader = BufReader::new(File::open(file)?);
             let mut buf = String::new();
-            let mut base_score = 0;
+            let mut parser = LineParser::new(
+                format.unwrap_or_else(|| InputFormat::from_path(file)),
+                file.display().to_string(),
+            );
 
             while reader.read_line(&mut buf)? > 0 {
-                let kv_pair = match parse_line(&buf) {
-                    None => continue,
-                    Some(res) => res,
-                };
-                match kv_pair {
-                    ("%base_score", Some(value)) => {
-                        if let Ok(value) = value.parse::<usize>() {
-                            base_score = value;
-                        }
-                    }
-                    (key, Some(value)) => res.push(Element {
-                        name: key.to_string(),
-                        value: value.to_string(),
-                        base_score,
-                    }),
-                    ("", None) => {} // Empty Line
-                    (key, None) => res.push(Element {
-                        name: key.to_string(),
-                        value: key.to_string(),
-                        base_score,
-                    }),
+                if let Some(element) = parser.parse(&buf) {
+                    res.push(element);
                 }
 
                 buf.clear();
@@ -194,7 +419,12 @@ impl ElementListBuilder {
                         res.push(Element {
                             value: name.clone(),
                             name,
+                            description: None,
+                            keywords: Vec::new(),
+                            icon: None,
+                            meta: None,
                             base_score: 0,
+                            source: Source::Path,
                         });
                     }
                 }
@@ -207,35 +437,16 @@ impl ElementListBuilder {
         Ok(res)
     }
 
-    async fn build_stdin() -> Result<Vec<Element>, std::io::Error> {
+    async fn build_stdin(format: InputFormat) -> Result<Vec<Element>, std::io::Error> {
         let stdin = io::stdin();
         let reader = io::BufReader::new(stdin);
         let mut lines = reader.lines();
         let mut res = Vec::new();
-        let mut base_score = 0;
+        let mut parser = LineParser::new(format, "stdin".to_string());
 
         while let Some(line) = lines.next_line().await? {
-            let kv_pair = match parse_line(&line) {
-                None => continue,
-                Some(res) => res,
-            };
-            match kv_pair {
-                ("%base_score", Some(value)) => {
-                    if let Ok(value) = value.parse::<usize>() {
-                        base_score = value;
-                    }
-                }
-                (key, Some(value)) => res.push(Element {
-                    name: key.to_string(),
-                    value: value.to_string(),
-                    base_score,
-                }),
-                ("", None) => {} // Empty Line
-                (key, None) => res.push(Element {
-                    name: key.to_string(),
-                    value: key.to_string(),
-                    base_score,
-                }),
+            if let Some(element) = parser.parse(&line) {
+                res.push(element);
             }
         }
 
@@ -260,6 +471,148 @@ fn parse_line(input: &str) -> Option<(&str, Option<&str>)> {
 mod tests {
     use super::*;
 
+    fn element(name: &str) -> Element {
+        Element {
+            name: name.to_string(),
+            value: name.to_string(),
+            description: None,
+            keywords: Vec::new(),
+            icon: None,
+            meta: None,
+            base_score: 0,
+            source: Source::Shell,
+        }
+    }
+
+    fn list(elements: Vec<Element>) -> ElementList {
+        ElementList {
+            inner: elements,
+            aliases: HashMap::new(),
+        }
+    }
+
+    fn names(results: Vec<&Element>) -> Vec<&str> {
+        results.iter().map(|x| x.name.as_str()).collect()
+    }
+
+    #[test]
+    fn search_description_test() {
+        let list = list(vec![
+            Element {
+                description: Some("Web Browser".to_string()),
+                ..element("firefox")
+            },
+            element("browsh"),
+        ]);
+
+        assert_eq!(names(list.search("brow", false)), ["browsh"]);
+        assert_eq!(names(list.search("brow", true)), ["browsh", "firefox"]);
+    }
+
+    #[test]
+    fn search_keywords_test() {
+        let list = list(vec![
+            Element {
+                keywords: vec!["calc".to_string()],
+                ..element("qalculate")
+            },
+            element("calc"),
+        ]);
+
+        // A name match scores higher than the same match in a keyword
+        assert_eq!(names(list.search("calc", false)), ["calc", "qalculate"]);
+    }
+
+    #[test]
+    fn aliases_test() {
+        let mut list = list(vec![element("Firefox"), element("qalculate")]);
+        let aliases = HashMap::from([
+            ("browser".to_string(), "firefox".to_string()),
+            ("web".to_string(), "unknown".to_string()),
+        ]);
+        list.set_aliases(&aliases);
+        assert_eq!(names(list.search("browser", false)), ["Firefox"]);
+        assert!(list.search("web", false).is_empty());
+
+        list.set_aliases(&HashMap::from([(
+            "calc".to_string(),
+            "qalculate".to_string(),
+        )]));
+        assert!(list.search("browser", false).is_empty());
+        assert_eq!(names(list.search("calc", false)), ["qalculate"]);
+    }
+
+    #[test]
+    fn parse_text_test() {
+        let mut parser = LineParser::new(InputFormat::Text, "stdin".to_string());
+        let entry = parser.parse("1\tcopied text").unwrap();
+        assert_eq!(entry.name, "1\tcopied text");
+        assert_eq!(entry.description, None);
+
+        let mut parser = LineParser::new(InputFormat::Tsv, "stdin".to_string());
+        assert_eq!(
+            parser.parse("Firefox = firefox --new-window\tWeb Browser\n"),
+            Some(Element {
+                value: "firefox --new-window".to_string(),
+                description: Some("Web Browser".to_string()),
+                ..element("Firefox")
+            })
+        );
+        let htop = parser.parse("htop\tProcess Viewer").unwrap();
+        assert_eq!(htop.value, "htop");
+        assert_eq!(htop.description.as_deref(), Some("Process Viewer"));
+        assert_eq!(parser.parse("htop\t").unwrap().description, None);
+    }
+
+    #[test]
+    fn parse_json_test() {
+        let mut parser = LineParser::new(InputFormat::Jsonl, "stdin".to_string());
+        assert_eq!(
+            parser
+                .try_parse(r#"{"name": "a=b", "description": "Desc", "icon": "x", "meta": {"id": 1}, "score": 3}"#)
+                .unwrap(),
+            Some(Element {
+                name: "a=b".to_string(),
+                value: "a=b".to_string(),
+                description: Some("Desc".to_string()),
+                keywords: Vec::new(),
+                icon: Some("x".to_string()),
+                meta: Some(serde_json::json!({"id": 1})),
+                base_score: 3,
+                source: Source::Shell,
+            })
+        );
+        assert_eq!(parser.try_parse("").unwrap(), None);
+        let err = parser.try_parse(r#"{"value": "foo"}"#).unwrap_err();
+        assert_eq!(err, "stdin:3:16: missing field `name`");
+
+        let element = parser
+            .try_parse(r#"{"name": "Htop", "exec": ["foot", "-e", "htop's"]}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(element.value, r"'foot' '-e' 'htop'\''s'");
+        assert_eq!(
+            element.source,
+            Source::Exec(vec!["foot".into(), "-e".into(), "htop's".into()])
+        );
+        let err = parser
+            .try_parse(r#"{"name": "a", "exec": []}"#)
+            .unwrap_err();
+        assert_eq!(err, "stdin:5: exec is empty");
+    }
+
+    #[test]
+    fn build_files_test() {
+        let file =
+            std::env::temp_dir().join(format!("kickoff-jsonl-test-{}.jsonl", std::process::id()));
+        std::fs::write(&file, "{\"name\": \"a\"}\n{\"name\": \n{\"name\": \"b\"}\n").unwrap();
+        // The broken second line is skipped
+        let elements = ElementListBuilder::build_files(std::slice::from_ref(&file), None).unwrap();
+        let names: Vec<&str> = elements.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+        std::fs::remove_file(file).unwrap();
+    }
+
     #[test]
     fn parse_line_test() {
         assert_eq!(parse_line("foobar"), Some(("foobar", None)));
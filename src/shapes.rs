@@ -0,0 +1,147 @@
+use crate::color::Color;
+use image::{Pixel, RgbaImage};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Rect {
+    pub const fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Shrinks the rectangle by `amount` on every side
+    pub fn shrink(&self, amount: f32) -> Self {
+        Self {
+            x: self.x + amount,
+            y: self.y + amount,
+            width: amount.mul_add(-2., self.width).max(0.),
+            height: amount.mul_add(-2., self.height).max(0.),
+        }
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    /// Cheap check if the pixel at `(x, y)` lies completely inside, ignoring the corners
+    fn contains_fully(&self, x: u32, y: u32, radius: f32) -> bool {
+        let (x, y) = (x as f32, y as f32);
+        let inside = |min: f32, max: f32, v: f32| v >= min.ceil() && v + 1. <= max.floor();
+        let (right, bottom) = (self.x + self.width, self.y + self.height);
+        (inside(self.x + radius, right - radius, x) && inside(self.y, bottom, y))
+            || (inside(self.x, right, x) && inside(self.y + radius, bottom - radius, y))
+    }
+
+    /// Share of the pixel at `(x, y)` covered by the rectangle with rounded corners.
+    /// Based on the signed distance to the outline, which gives a one pixel wide antialiased edge.
+    fn coverage(&self, x: u32, y: u32, radius: f32) -> f32 {
+        if self.width <= 0. || self.height <= 0. {
+            return 0.;
+        }
+        let radius = radius.min(self.width / 2.).min(self.height / 2.).max(0.);
+        let half_width = self.width / 2.;
+        let half_height = self.height / 2.;
+        let qx = (x as f32 + 0.5 - (self.x + half_width)).abs() - half_width + radius;
+        let qy = (y as f32 + 0.5 - (self.y + half_height)).abs() - half_height + radius;
+        let outside = qx.max(0.).hypot(qy.max(0.));
+        let inside = qx.max(qy).min(0.);
+
+        (0.5 - (outside + inside - radius)).clamp(0., 1.)
+    }
+
+    /// Pixel bounds `(x_min, y_min, x_max, y_max)` clipped to the image, max exclusive
+    fn bounds(&self, image: &RgbaImage) -> (u32, u32, u32, u32) {
+        let clip = |v: f32, max: u32| (v.max(0.) as u32).min(max);
+        (
+            clip(self.x.floor(), image.width()),
+            clip(self.y.floor(), image.height()),
+            clip((self.x + self.width).ceil(), image.width()),
+            clip((self.y + self.height).ceil(), image.height()),
+        )
+    }
+}
+
+fn blend(image: &mut RgbaImage, x: u32, y: u32, color: &Color, coverage: f32) {
+    if coverage <= 0. {
+        return;
+    }
+    let alpha = (f32::from(color.3) * coverage).round() as u8;
+    image
+        .get_pixel_mut(x, y)
+        .blend(&image::Rgba([color.0, color.1, color.2, alpha]));
+}
+
+/// Fills `rect` with `color`, corners are rounded by `radius`
+pub fn fill_rect(image: &mut RgbaImage, rect: Rect, radius: f32, color: &Color) {
+    if color.3 == 0 {
+        return;
+    }
+    let (x_min, y_min, x_max, y_max) = rect.bounds(image);
+    for y in y_min..y_max {
+        for x in x_min..x_max {
+            if color.3 == 255 && rect.contains_fully(x, y, radius) {
+                image.put_pixel(x, y, color.to_rgba());
+            } else {
+                blend(image, x, y, color, rect.coverage(x, y, radius));
+            }
+        }
+    }
+}
+
+/// Draws a border of `width` pixels along the inside of `rect`
+pub fn stroke_rect(image: &mut RgbaImage, rect: Rect, radius: f32, width: f32, color: &Color) {
+    if color.3 == 0 || width <= 0. {
+        return;
+    }
+    let inner = rect.shrink(width);
+    let inner_radius = (radius - width).max(0.);
+    let (x_min, y_min, x_max, y_max) = rect.bounds(image);
+    for y in y_min..y_max {
+        for x in x_min..x_max {
+            if inner.contains_fully(x, y, inner_radius) {
+                continue;
+            }
+            let coverage = rect.coverage(x, y, radius) - inner.coverage(x, y, inner_radius);
+            blend(image, x, y, color, coverage);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coverage_test() {
+        let rect = Rect::new(0., 0., 10., 10.);
+        assert!((rect.coverage(5, 5, 0.) - 1.).abs() < f32::EPSILON);
+        assert!(rect.coverage(10, 5, 0.).abs() < f32::EPSILON);
+        // corners are cut off by the radius
+        assert!(rect.coverage(0, 0, 5.) < 0.5);
+        assert!((rect.coverage(1, 5, 5.) - 1.).abs() < f32::EPSILON);
+
+        assert!(rect.contains_fully(1, 5, 1.));
+        assert!(!rect.contains_fully(0, 0, 5.));
+        assert!(!rect.contains_fully(9, 9, 0.5));
+    }
+
+    #[test]
+    fn stroke_rect_test() {
+        let mut image = RgbaImage::new(10, 10);
+        let color = Color(255, 0, 0, 255);
+        stroke_rect(&mut image, Rect::new(0., 0., 10., 10.), 0., 2., &color);
+        assert_eq!(image.get_pixel(0, 5).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(1, 1).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(5, 5).0, [0, 0, 0, 0]);
+    }
+}
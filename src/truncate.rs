@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+
+const ELLIPSIS: char = '…';
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Truncation {
+    /// `Some long na…`
+    #[default]
+    End,
+    /// `…ong name`
+    Start,
+    /// `Some…name`, for paths the file name is kept: `/usr/…/bin/kickoff`
+    Middle,
+}
+
+/// Shortens `text` until it fits into `max_width`, the removed characters are replaced by an ellipsis.
+/// Characters at the indices in `keep` are moved into the visible part whenever possible.
+/// `measure` returns the rendered width of a string.
+pub fn truncate(
+    text: &str,
+    max_width: f32,
+    mode: Truncation,
+    keep: &[usize],
+    measure: impl Fn(&str) -> f32,
+) -> String {
+    if measure(text) <= max_width {
+        return text.to_string();
+    }
+    let chars: Vec<char> = text.chars().collect();
+
+    // Search for the smallest number of removed characters that fits
+    let (mut low, mut high) = (1, chars.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        if measure(&elide(&chars, placement(&chars, mid, mode, keep), mid)) <= max_width {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+
+    elide(&chars, placement(&chars, high, mode, keep), high)
+}
+
+fn elide(chars: &[char], start: usize, count: usize) -> String {
+    chars[..start]
+        .iter()
+        .chain(&[ELLIPSIS])
+        .chain(&chars[start + count..])
+        .collect()
+}
+
+/// Returns the start of the `count` characters that are replaced
+fn placement(chars: &[char], count: usize, mode: Truncation, keep: &[usize]) -> usize {
+    let max_start = chars.len() - count;
+    let preferred = match mode {
+        Truncation::End => max_start,
+        Truncation::Start => 0,
+        Truncation::Middle => {
+            // Shorten the directories of a path right before the file name, if it still fits
+            chars
+                .iter()
+                .rposition(|c| *c == '/')
+                .filter(|i| *i > 0 && count <= *i)
+                .map_or(max_start / 2, |i| i - count)
+        }
+    };
+
+    let hides_kept = |start: usize| keep.iter().any(|i| (start..start + count).contains(i));
+    if !hides_kept(preferred) {
+        return preferred;
+    }
+
+    (0..=max_start)
+        .filter(|start| !hides_kept(*start))
+        .min_by_key(|start| start.abs_diff(preferred))
+        .unwrap_or(preferred)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn width(text: &str) -> f32 {
+        text.chars().count() as f32
+    }
+
+    #[test]
+    fn truncate_test() {
+        let text = "Firefox Developer Edition";
+        assert_eq!(truncate(text, 30., Truncation::End, &[], width), text);
+        assert_eq!(
+            truncate(text, 10., Truncation::End, &[], width),
+            "Firefox D…"
+        );
+        assert_eq!(
+            truncate(text, 10., Truncation::Start, &[], width),
+            "…r Edition"
+        );
+        assert_eq!(
+            truncate(text, 10., Truncation::Middle, &[], width),
+            "Fire…ition"
+        );
+    }
+
+    #[test]
+    fn truncate_path_test() {
+        let path = "/usr/lib/firefox-developer-edition/firefox";
+        assert_eq!(
+            truncate(path, 20., Truncation::Middle, &[], width),
+            "/usr/lib/fi…/firefox"
+        );
+        // The file name itself is too long, fall back to the middle of the whole text
+        assert_eq!(
+            truncate("/a/verylongfilename", 10., Truncation::Middle, &[], width),
+            "/a/v…ename"
+        );
+    }
+
+    #[test]
+    fn truncate_keep_test() {
+        let text = "Firefox Developer Edition";
+        // "Edi" is matched and moved into view
+        assert_eq!(
+            truncate(text, 10., Truncation::End, &[18, 19, 20], width),
+            "Fi…Edition"
+        );
+        assert_eq!(
+            truncate(text, 10., Truncation::Start, &[0, 1], width),
+            "Fi…Edition"
+        );
+        assert_eq!(
+            truncate(text, 10., Truncation::End, &[0, 24], width),
+            "Firefox …n"
+        );
+        // Not all matches fit, keep the preferred placement
+        assert_eq!(
+            truncate(text, 10., Truncation::End, &[0, 12, 24], width),
+            "Firefox D…"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use log::{debug, warn};
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+/// Watches config files for changes. Files are watched through their directories,
+/// since editors usually replace a file instead of writing to it.
+pub struct ConfigWatcher {
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    files: Vec<PathBuf>,
+    drop_in_dirs: Vec<PathBuf>,
+}
+
+impl ConfigWatcher {
+    pub fn new() -> io::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        Ok(Self {
+            inotify,
+            dirs: HashMap::new(),
+            files: Vec::new(),
+            drop_in_dirs: Vec::new(),
+        })
+    }
+
+    /// File descriptor that gets readable on changes, for the event loop
+    pub fn fd(&self) -> io::Result<OwnedFd> {
+        self.inotify.as_fd().try_clone_to_owned()
+    }
+
+    /// Watches `files`, which don't need to exist yet, and the drop-in directories `drop_in_dirs`,
+    /// in which any `.toml` file counts as change.
+    /// Replaces the files watched before, directories stay watched.
+    pub fn watch(&mut self, files: &[PathBuf], drop_in_dirs: &[PathBuf]) {
+        self.files = files.iter().map(|file| canonicalize(file)).collect();
+        self.drop_in_dirs = drop_in_dirs
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .collect();
+        let parents: Vec<PathBuf> = self
+            .files
+            .iter()
+            .filter_map(|file| file.parent().map(Path::to_path_buf))
+            .collect();
+        for dir in parents.iter().chain(&self.drop_in_dirs) {
+            if !dir.is_dir() || self.dirs.values().any(|watched| watched == dir) {
+                continue;
+            }
+            let flags = AddWatchFlags::IN_CLOSE_WRITE
+                | AddWatchFlags::IN_MOVED_TO
+                | AddWatchFlags::IN_MOVED_FROM
+                | AddWatchFlags::IN_DELETE;
+            match self.inotify.add_watch(dir, flags) {
+                Ok(descriptor) => {
+                    debug!("Watching {} for config changes", dir.display());
+                    self.dirs.insert(descriptor, dir.clone());
+                }
+                Err(e) => warn!("Unable to watch {}: {e}", dir.display()),
+            }
+        }
+    }
+
+    /// Reads the pending events, returns whether a config file changed
+    pub fn changed(&self) -> bool {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return false,
+            Err(e) => {
+                warn!("Unable to read config changes: {e}");
+                return false;
+            }
+        };
+        events.iter().any(|event| {
+            let (Some(dir), Some(name)) = (self.dirs.get(&event.wd), &event.name) else {
+                return false;
+            };
+            let path = dir.join(name);
+            self.files.contains(&path)
+                || (self.drop_in_dirs.contains(dir) && path.extension() == Some("toml".as_ref()))
+        })
+    }
+}
+
+/// Canonicalizes the directory of `file` if the file itself doesn't exist
+fn canonicalize(file: &Path) -> PathBuf {
+    file.canonicalize()
+        .ok()
+        .or_else(|| {
+            let dir = file.parent()?.canonicalize().ok()?;
+            Some(dir.join(file.file_name()?))
+        })
+        .unwrap_or_else(|| file.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn watch_test() {
+        let dir = std::env::temp_dir().join(format!("kickoff-watch-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.txt");
+        fs::write(&config, "").unwrap();
+        let drop_in_dir = dir.join("config.d");
+        fs::create_dir_all(&drop_in_dir).unwrap();
+
+        let mut watcher = ConfigWatcher::new().unwrap();
+        watcher.watch(
+            &[config.clone(), dir.join("config.toml")],
+            std::slice::from_ref(&drop_in_dir),
+        );
+        assert!(!watcher.changed());
+
+        fs::write(dir.join(".config.txt.swp"), "").unwrap();
+        assert!(!watcher.changed());
+        // Replaced like editors do
+        fs::write(dir.join("config.txt.new"), "padding = 1").unwrap();
+        fs::rename(dir.join("config.txt.new"), &config).unwrap();
+        assert!(watcher.changed());
+        // Only drop-ins count, not any `.toml` file next to the config
+        fs::write(dir.join("other.toml"), "").unwrap();
+        assert!(!watcher.changed());
+        fs::write(drop_in_dir.join("drop-in.toml"), "").unwrap();
+        assert!(watcher.changed());
+        fs::write(drop_in_dir.join("notes.txt"), "").unwrap();
+        assert!(!watcher.changed());
+        // Config files that didn't exist before
+        fs::write(dir.join("config.toml"), "").unwrap();
+        assert!(watcher.changed());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}